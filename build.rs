@@ -1,32 +1,266 @@
 //! build.rs --- shed build script
 /*!
 this script provides the 'DEMON_VERSION' variable for all builds,
-which adds a Mercurial commit hash to the package version.
+which adds a Mercurial commit hash to the package version. The hash
+is taken verbatim from 'SOURCE_COMMIT' when set (for builds where
+'.hg' is stripped, like Nix and Docker), else from the working
+copy. Without either it is just the package version. Reading the
+working copy is retried a few times, and if hg still fails the hash
+is recorded as 'unknown' with a warning instead of failing the build.
+A '-dirty' suffix is added when the working copy has uncommitted
+changes to 'src', 'build.rs', or 'Cargo.toml'.
 
-When 'PROFILE'='release' also generate bash, zsh, and powershell
-completions.
+Additional build metadata is provided for 'shc version --verbose':
+'SHED_COMMIT', 'SHED_RUSTC_VERSION', 'SHED_TARGET', 'SHED_PROFILE',
+'SHED_BUILD_TIMESTAMP', and 'SHED_BUILD_HOST'. The timestamp is the
+UTC time this script last ran, not when the programs were linked, and
+is taken from 'SOURCE_DATE_EPOCH' when set. For reproducible builds set
+'SHED_NO_BUILD_HOST' to leave out the hostname.
+
+When 'PROFILE'='release' also generate bash, zsh, fish, and
+powershell completions. They are written to 'SHED_COMPLETIONS_DIR'
+when set, else the package root. Release builds also write roff man
+pages for each program and subcommand to 'OUT_DIR'. Set
+'SHED_NO_COMPLETIONS' to skip both.
 */
 
-use rlib::util::{
-  bs::version::generate_cargo_keys,
-  cli::comp_gen::{generate_to, Bash, PowerShell, Zsh},
-  Result,
-};
+use rlib::util::Result;
 
-use std::{env, fs, path};
+use std::{
+  env, fs,
+  process::Command,
+  thread,
+  time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
 include!("src/cli.rs");
 
+/// Run `cmd` with `args`, returning its trimmed stdout on success
+fn output(cmd: &str, args: &[&str]) -> Option<String> {
+  let out = Command::new(cmd).args(args).output().ok()?;
+  if out.status.success() {
+    Some(String::from_utf8_lossy(&out.stdout).trim().to_string())
+  } else {
+    None
+  }
+}
+
+/// Return the working copy's hg id. hg can fail intermittently (lock
+/// contention on CI), so retry with a short backoff.
+fn hg_id() -> Option<String> {
+  for i in 0..3 {
+    if i > 0 {
+      thread::sleep(Duration::from_millis(100 << i));
+    }
+    if let Some(id) = output("hg", &["id", "-i"]) {
+      return Some(id);
+    }
+  }
+  None
+}
+
+/// Return true if `hg status` shows modified, added, removed, or
+/// missing files among the sources the programs are built from, which
+/// are also the paths that rerun this script. Failures are warned
+/// about and treated as clean.
+fn hg_dirty() -> bool {
+  match output("hg", &["status", "-mard", "src", "build.rs", "Cargo.toml"]) {
+    Some(s) => !s.is_empty(),
+    None => {
+      println!("cargo:warning=unable to read the hg status, assuming a clean working copy");
+      false
+    }
+  }
+}
+
+/// Format `secs` since the Unix epoch as an RFC 3339 UTC timestamp
+fn utc(secs: u64) -> String {
+  let (days, rem) = ((secs / 86400) as i64, secs % 86400);
+  // civil date from days since 1970-01-01, see
+  // http://howardhinnant.github.io/date_algorithms.html
+  let z = days + 719468;
+  let era = z.div_euclid(146097);
+  let doe = z.rem_euclid(146097);
+  let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+  let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+  let mp = (5 * doy + 2) / 153;
+  let d = doy - (153 * mp + 2) / 5 + 1;
+  let m = if mp < 10 { mp + 3 } else { mp - 9 };
+  let y = yoe + era * 400 + if m <= 2 { 1 } else { 0 };
+  format!(
+    "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+    y,
+    m,
+    d,
+    rem / 3600,
+    rem / 60 % 60,
+    rem % 60
+  )
+}
+
+/// Return the hostname of the build machine
+fn hostname() -> Option<String> {
+  env::var("HOSTNAME")
+    .ok()
+    .or_else(|| env::var("COMPUTERNAME").ok())
+    .filter(|h| !h.is_empty())
+    .or_else(|| output("hostname", &[]))
+}
+
+/// Escape `s` for use in roff text
+fn roff(s: &str) -> String {
+  s.replace('\\', "\\\\").replace('-', "\\-")
+}
+
+/// Write a man page for `app` to '<dir>/<name>.1', and a page for each
+/// of its subcommands as '<name>-<subcommand>.1'. Pages which are
+/// already up to date aren't rewritten.
+fn generate_man(app: &App, name: &str, dir: &Path) -> Result<()> {
+  let subs: Vec<&App> = app.get_subcommands().collect();
+  let mut page = format!(
+    ".TH {} 1 \"\" \"{} {}\"\n.SH NAME\n{} \\- {}\n.SH SYNOPSIS\n\\fB{}\\fR [OPTIONS]{}\n",
+    roff(&name.to_uppercase()),
+    name,
+    version(),
+    roff(name),
+    roff(app.get_about().unwrap_or_default()),
+    roff(name),
+    if subs.is_empty() { "" } else { " [SUBCOMMAND]" },
+  );
+  let args: Vec<&Arg> = app.get_arguments().collect();
+  if !args.is_empty() {
+    page.push_str(".SH OPTIONS\n");
+    for arg in args {
+      let flags: Vec<String> = match (arg.get_short(), arg.get_long()) {
+        (None, None) => vec![format!("<{}>", arg.get_name())],
+        (s, l) => s
+          .map(|s| format!("-{}", s))
+          .into_iter()
+          .chain(l.map(|l| format!("--{}", l)))
+          .collect(),
+      };
+      let flags: Vec<String> = flags
+        .iter()
+        .map(|f| format!("\\fB{}\\fR", roff(f)))
+        .collect();
+      page.push_str(&format!(
+        ".TP\n{}\n{}\n",
+        flags.join(", "),
+        roff(arg.get_about().unwrap_or_default())
+      ));
+    }
+  }
+  if !subs.is_empty() {
+    page.push_str(".SH SUBCOMMANDS\n");
+    for sub in subs.iter() {
+      page.push_str(&format!(
+        ".TP\n\\fB{}\\fR\n{} See \\fB{}\\-{}\\fR(1).\n",
+        roff(sub.get_name()),
+        roff(sub.get_about().unwrap_or_default()),
+        roff(name),
+        roff(sub.get_name()),
+      ));
+    }
+  }
+  let path = dir.join(format!("{}.1", name));
+  if fs::read_to_string(&path).ok().as_deref() != Some(page.as_str()) {
+    fs::write(path, page)?;
+  }
+  for sub in subs {
+    generate_man(sub, &format!("{}-{}", name, sub.get_name()), dir)?;
+  }
+  Ok(())
+}
+
 fn main() -> Result<()> {
-  generate_cargo_keys();
-  let o: path::PathBuf = env!("CARGO_MANIFEST_DIR").into();
-  if !o.exists() {
-    fs::create_dir(&o)?;
-  }
-  let c = (&mut build_cli(), "shc", &o);
-  generate_to(Bash, c.0, c.1, c.2)?;
-  generate_to(Zsh, c.0, c.1, c.2)?;
-  generate_to(PowerShell, c.0, c.1, c.2)?;
+  let pkg = env!("CARGO_PKG_VERSION");
+  let commit = match env::var("SOURCE_COMMIT") {
+    Ok(c) if !c.is_empty() => {
+      println!("cargo:rustc-env=DEMON_VERSION={}-{}", pkg, c);
+      Some(c)
+    }
+    _ if Path::new(env!("CARGO_MANIFEST_DIR")).join(".hg").exists() => match hg_id() {
+      Some(id) => {
+        // hg marks a dirty working copy with '+', which is replaced
+        // by the more explicit suffix
+        let id = id.trim_end_matches('+').to_string();
+        let dirty = if hg_dirty() { "-dirty" } else { "" };
+        println!("cargo:rustc-env=DEMON_VERSION={}-{}{}", pkg, id, dirty);
+        Some(id)
+      }
+      None => {
+        println!("cargo:warning=unable to read the hg commit hash, using 'unknown'");
+        println!("cargo:rustc-env=DEMON_VERSION={}-unknown", pkg);
+        None
+      }
+    },
+    _ => {
+      println!("cargo:rustc-env=DEMON_VERSION={}", pkg);
+      None
+    }
+  };
+  let rustc = env::var("RUSTC").unwrap_or_else(|_| "rustc".to_string());
+  let built = match env::var("SOURCE_DATE_EPOCH") {
+    Ok(e) => e
+      .parse()
+      .map_err(|_| format!("invalid SOURCE_DATE_EPOCH '{}'", e))?,
+    Err(_) => SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+  };
+  let host = match env::var_os("SHED_NO_BUILD_HOST") {
+    Some(v) if !v.is_empty() => Some("omitted".to_string()),
+    _ => hostname(),
+  };
+  for (k, v) in [
+    ("SHED_COMMIT", commit),
+    ("SHED_RUSTC_VERSION", output(&rustc, &["--version"])),
+    ("SHED_TARGET", env::var("TARGET").ok()),
+    ("SHED_PROFILE", env::var("PROFILE").ok()),
+    ("SHED_BUILD_TIMESTAMP", Some(utc(built))),
+    ("SHED_BUILD_HOST", host),
+  ] {
+    println!(
+      "cargo:rustc-env={}={}",
+      k,
+      v.unwrap_or_else(|| "unknown".to_string())
+    );
+  }
+  let skip = env::var_os("SHED_NO_COMPLETIONS").map_or(false, |v| !v.is_empty());
+  if env::var("PROFILE").as_deref() == Ok("release") && !skip {
+    let o: PathBuf = match env::var_os("SHED_COMPLETIONS_DIR") {
+      Some(d) => d.into(),
+      None => env!("CARGO_MANIFEST_DIR").into(),
+    };
+    if !o.exists() {
+      fs::create_dir_all(&o).map_err(|e| {
+        format!(
+          "failed to create completions directory {}: {}",
+          o.display(),
+          e
+        )
+      })?;
+    }
+    for (app, name) in [(&mut build_cli(), "shc"), (&mut build_server_cli(), "shs")] {
+      generate_completions(app, name, &o)?;
+    }
+    let man: PathBuf = env::var_os("OUT_DIR").ok_or("OUT_DIR is not set")?.into();
+    generate_man(&build_cli(), "shc", &man)?;
+    generate_man(&build_server_cli(), "shs", &man)?;
+  }
   println!("cargo:rerun-if-changed=build.rs");
+  println!("cargo:rerun-if-changed=src/cli.rs");
+  if Path::new(env!("CARGO_MANIFEST_DIR")).join(".hg").exists() {
+    // the commit changes with hg commands, which update the dirstate,
+    // and the dirty state with any edit to the sources
+    println!("cargo:rerun-if-changed=.hg/dirstate");
+    println!("cargo:rerun-if-changed=src");
+    println!("cargo:rerun-if-changed=Cargo.toml");
+  }
+  println!("cargo:rerun-if-env-changed=PROFILE");
+  println!("cargo:rerun-if-env-changed=OUT_DIR");
+  println!("cargo:rerun-if-env-changed=SHED_COMPLETIONS_DIR");
+  println!("cargo:rerun-if-env-changed=SHED_NO_COMPLETIONS");
+  println!("cargo:rerun-if-env-changed=SOURCE_COMMIT");
+  println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+  println!("cargo:rerun-if-env-changed=SHED_NO_BUILD_HOST");
   Ok(())
 }
@@ -4,8 +4,9 @@
 with a 'cfg::Config'. This type is used to call other functions from
 'rlib', 'tenex', and internal modules.
 */
-use crate::Config;
+use crate::{build_cli, config, logger, server, version, Config, LogConfig, BUILD_INFO};
 
+use flexi_logger::LoggerHandle;
 use rlib::{
   db::{registry::Registry, Error as DbErr},
   flate,
@@ -18,22 +19,32 @@ use rlib::{
     },
     Error as KErr,
   },
-  logger::log::{error, info},
+  logger::log::{debug, error, info},
   net::{
     reqwest::{Client, Url},
     Error as NetErr,
   },
   obj::Error,
-  util::{cli::ArgMatches, Result},
+  util::{
+    cli::{
+      comp_gen::{generate, generate_to, Bash, Elvish, Fish, Generator, PowerShell, Zsh},
+      ArgMatches,
+    },
+    Result,
+  },
 };
 
 use tenex::{ipapi::get_ip, nws::weather_report};
 
 use std::{
+  cell::RefCell,
   env,
-  fs::{create_dir, remove_file, File},
+  fs::{self, create_dir, remove_file, File},
+  io::{Read, Seek, SeekFrom, Write},
   path::{Path, PathBuf},
+  process::{Command, Stdio},
   str::FromStr,
+  time::{Duration, Instant},
 };
 
 /// HTTP file download client
@@ -55,52 +66,112 @@ async fn download<P: AsRef<Path>>(client: &Client, url: Url, path: P) -> Result<
   Ok(())
 }
 
+/// Write completions for `shc` using generator `gen`, either to
+/// `out_dir` or stdout.
+fn completions<G: Generator>(gen: G, out_dir: Option<&str>) -> Result<()> {
+  let mut cli = build_cli();
+  match out_dir {
+    Some(o) => {
+      let p = generate_to(gen, &mut cli, "shc", o)?;
+      println!("wrote completions to {}", p.display());
+    }
+    None => generate(gen, &mut cli, "shc", &mut std::io::stdout()),
+  }
+  Ok(())
+}
+
+/// Return the last `n` lines of the file at `path` and the length of
+/// the file, reading backwards from the end so that only those lines
+/// are loaded.
+fn tail(path: &Path, n: usize) -> std::io::Result<(Vec<u8>, u64)> {
+  let mut f = File::open(path)?;
+  let len = f.seek(SeekFrom::End(0))?;
+  let mut buf = vec![];
+  let mut pos = len;
+  while pos > 0 && n > 0 {
+    let start = pos.saturating_sub(8192);
+    let mut chunk = vec![0; (pos - start) as usize];
+    f.seek(SeekFrom::Start(start))?;
+    f.read_exact(&mut chunk)?;
+    chunk.append(&mut buf);
+    buf = chunk;
+    pos = start;
+    // the newline ending the last line doesn't start another one
+    let body = buf.strip_suffix(b"\n").unwrap_or(&buf);
+    let nth = body
+      .iter()
+      .enumerate()
+      .rev()
+      .filter(|(_, b)| **b == b'\n')
+      .nth(n - 1)
+      .map(|(i, _)| i);
+    if let Some(i) = nth {
+      buf.drain(..=i);
+      return Ok((buf, len));
+    }
+  }
+  Ok((buf, len))
+}
+
 /// shc application
 pub struct App<'a> {
   /// User configuration
   pub cfg: Config,
   /// CLI args
   pub cli: &'a ArgMatches,
+  /// Logger handle, kept alive for the lifetime of the App
+  pub log: Option<LoggerHandle>,
+  /// Only log changes to the filesystem instead of making them
+  pub dry_run: bool,
+  /// Changes skipped because of `dry_run`
+  planned: RefCell<Vec<String>>,
 }
 
 impl<'a> App<'a> {
   /// Generate a new `App` instance from CLI args
-  pub fn new(cli: &'a ArgMatches) -> Result<Self, KErr> {
-    // set config
-    let cfg = match cli.value_of("config") {
-      Some(cfg) => {
-        info!("custom cfg: {}", cfg);
-        Config::load(cfg)?
-      }
-      None => {
-        let env = Path::new(env!("CFG")).join("shed.cfg");
-        if env.is_file() {
-          Config::load(env)?
-        } else {
-          Config::new()
-        }
+  pub fn new(cli: &'a ArgMatches) -> Result<Self> {
+    // set config, falling back to defaults for commands which
+    // diagnose a broken config
+    let cfg = match Config::resolve(cli) {
+      Ok(cfg) => cfg,
+      Err(e) if matches!(cli.subcommand_name(), Some("config" | "doctor")) => {
+        // the logger isn't set up yet
+        eprintln!("{}", e);
+        Config::new()
       }
+      Err(e) => return Err(e),
     };
 
-    info!("App Config: {:?}", cfg);
-
-    let log_lvl = cli.occurrences_of("log_level");
-    let lvl = match log_lvl {
-      0 => "warn",
-      1 => "info",
-      2 => "debug",
-      3.. => "trace",
+    // set up logging like shs, so 'shc serve' behaves the same
+    let log = match cli.subcommand_name() {
+      // don't write to the log file being read
+      Some("logs") => logger::try_init(&LogConfig {
+        file: None,
+        target: None,
+        ..cfg.log.clone()
+      })?,
+      _ => logger::try_init(&cfg.log)?,
     };
 
-    let shed_path: PathBuf = cfg.path.to_path_buf();
-    match shed_path.join("data/log").to_str() {
-      Some(p) => {
-        rlib::logger::file(lvl, p, "shc").expect("logger init failed");
-      }
-      None => rlib::logger::flexi(lvl).expect("logger init failed"),
-    };
+    debug!("App Config: {:?}", cfg);
 
-    Ok(App { cfg, cli })
+    Ok(App {
+      cfg,
+      cli,
+      log,
+      dry_run: cli.is_present("dry_run"),
+      planned: RefCell::new(vec![]),
+    })
+  }
+
+  /// Return true if `action` should be performed. In a dry run it is
+  /// logged and recorded for the summary instead.
+  fn act(&self, action: String) -> bool {
+    if self.dry_run {
+      info!("dry run: would {}", action);
+      self.planned.borrow_mut().push(action);
+    }
+    !self.dry_run
   }
 
   /// Matches on any subcommands and execute additional methods
@@ -112,7 +183,7 @@ impl<'a> App<'a> {
           if opt.is_present("db") {
             self.init_db()?;
           } else {
-            self.init_cfg()?;
+            self.init_cfg(opt)?;
           }
         }
         ("build", _) => {
@@ -178,8 +249,10 @@ impl<'a> App<'a> {
 	      // ['https', '//site.com']. Don't forget to account for
 	      // this in the parsing function!
               let s: Vec<&str> = i.split(":").collect(); 
-              info!("downloading {} from {}...", s[1], s[0]);
-              self.dl(s[0], s[1]).await?;
+              if self.act(format!("download {} from {}", s[1], s[0])) {
+                info!("downloading {} from {}...", s[1], s[0]);
+                self.dl(s[0], s[1]).await?;
+              }
             }
             None => {
               error!("an object URI is required!");
@@ -187,11 +260,13 @@ impl<'a> App<'a> {
           };
         }
         ("serve", opt) => {
-          println!("starting server...");
           if let Some(p) = opt.value_of("package") {
             println!("{:#?}", p);
           }
-          self.serve().await?;
+          match opt.value_of("engine") {
+            Some(_) => self.serve(opt).await?,
+            None => self.run_server(opt).await?,
+          }
         }
         // Compression
         ("pack", opt) => {
@@ -205,16 +280,20 @@ impl<'a> App<'a> {
             } else {
               o.to_owned()
             };
-            info!("packing: {} => {} ", i, o);
-            flate::pack(i, o, None);
+            if self.act(format!("pack {} into {}", i, o)) {
+              info!("packing: {} => {} ", i, o);
+              flate::pack(i, o, None);
+            }
           } else if Path::new(i).is_file() {
             let o = if o.eq(".") {
               format!("{}.{}", i, "z")
             } else {
               o.to_owned()
             };
-            info!("compressing file: {} => {} ", i, o);
-            flate::compress(i, o)?;
+            if self.act(format!("compress {} into {}", i, o)) {
+              info!("compressing file: {} => {} ", i, o);
+              flate::compress(i, o)?;
+            }
           }
         }
         ("unpack", opt) => {
@@ -223,14 +302,59 @@ impl<'a> App<'a> {
             &opt.value_of("output").unwrap(),
           );
           if Path::new(i).is_file() {
-            println!("unpacking: {} => {} ", i, o);
             if opt.is_present("replace") {
-              flate::unpack_replace(i, o);
-            } else {
+              if self.act(format!("unpack {} into {}, removing {}", i, o, i)) {
+                println!("unpacking: {} => {} ", i, o);
+                flate::unpack_replace(i, o);
+              }
+            } else if self.act(format!("unpack {} into {}", i, o)) {
+              println!("unpacking: {} => {} ", i, o);
               flate::unpack(i, o);
             }
           }
         }
+        ("config", opt) => match opt.subcommand() {
+          Some(("check", o)) => self.check_cfg(o)?,
+          Some(("default", _)) => print!("{}", config::DEFAULT_CONFIG),
+          _ => (),
+        },
+        ("env", opt) => self.env(opt)?,
+        ("doctor", opt) => self.doctor(opt)?,
+        ("logs", opt) => self.logs(opt).await?,
+        ("selftest", _) => self.selftest().await?,
+        ("version", opt) => {
+          if opt.occurrences_of("verbose") > 0 {
+            println!("version={}", version());
+            for (k, v) in BUILD_INFO {
+              println!("{}={}", k, v);
+            }
+          } else {
+            println!("{}", version());
+          }
+        }
+        ("daemon", opt) => self.daemon(opt).await?,
+        ("completions", opt) if opt.is_present("list") => {
+          for s in crate::cli::SHELLS {
+            println!("{}", s);
+          }
+        }
+        ("completions", opt) => {
+          let o = opt.value_of("out_dir");
+          let write = match o {
+            Some(o) => self.act(format!("write completions to {}", o)),
+            None => true,
+          };
+          match opt.value_of("shell") {
+            _ if !write => (),
+            Some("bash") => completions(Bash, o)?,
+            Some("zsh") => completions(Zsh, o)?,
+            Some("fish") => completions(Fish, o)?,
+            Some("powershell") => completions(PowerShell, o)?,
+            Some("elvish") => completions(Elvish, o)?,
+            Some(s) => return Err(format!("unknown shell '{}'", s).into()),
+            None => return Err("a shell is required!".into()),
+          }
+        }
         ("edit", _) => self.edit().await?,
         ("clean", _opt) => {}
         (&_, _) => {
@@ -238,15 +362,31 @@ impl<'a> App<'a> {
         }
       }
     }
+    if self.dry_run {
+      let planned = self.planned.borrow();
+      if planned.is_empty() {
+        println!("dry run: no changes planned");
+      } else {
+        println!("dry run: {} planned changes", planned.len());
+        for p in planned.iter() {
+          println!("  {}", p);
+        }
+      }
+    }
     Ok(())
   }
 
   /// Create the `shed` directory tree
   pub fn build_dirs(self) -> Result<()> {
     let base = &self.cfg.path;
-    create_dir(base)?;
+    if self.act(format!("create directory {}", base.display())) {
+      create_dir(base)?;
+    }
     for i in ["stash", "store", "src", "lab", "data", "data/log"] {
-      create_dir(base.join(i))?;
+      let dir = base.join(i);
+      if self.act(format!("create directory {}", dir.display())) {
+        create_dir(dir)?;
+      }
     }
     Ok(())
   }
@@ -257,33 +397,259 @@ impl<'a> App<'a> {
     Ok(())
   }
 
-  /// Initialize a configuration from cli
-  pub fn init_cfg(&'a self) -> Result<()> {
-    let p: PathBuf = match self.cli.value_of("path") {
+  /// Initialize a configuration and data directory from cli. Existing
+  /// files are only overwritten with `--force`.
+  pub fn init_cfg(&'a self, opt: &ArgMatches) -> Result<()> {
+    let fmt = opt.value_of("fmt").unwrap_or("toml");
+    let p: PathBuf = match opt.value_of("path") {
       Some(p) => p.into(),
-      None => "~/.config/shed.cfg".into(),
+      None => config::default_path()
+        .ok_or("unable to determine the config directory")?
+        .with_extension(fmt),
     };
-    if !p.exists() {
-      println!("writing shed.cfg to {}...", p.display());
-      match self.cli.value_of("fmt") {
-        Some("ron") | None => self.cfg.write(&p, None)?,
-        Some("json") => self.cfg.write(&p, Some("json"))?,
-        Some("bin") => self.cfg.write(&p, Some("bin"))?,
-        Some(_) => error!("unknown configuration type"),
+    // `Config::load` picks the format from the extension
+    if p.extension().and_then(|e| e.to_str()) != Some(fmt) {
+      return Err(
+        format!(
+          "{} must have a .{} extension to be loaded",
+          p.display(),
+          fmt
+        )
+        .into(),
+      );
+    }
+    if p.exists() && !opt.is_present("force") {
+      return Err(format!("{} already exists, use -f to override", p.display()).into());
+    }
+    if let Some(dir) = p.parent().filter(|d| !d.exists()) {
+      if self.act(format!("create directory {}", dir.display())) {
+        fs::create_dir_all(dir)?;
+      }
+    }
+    if self.act(format!("write config to {}", p.display())) {
+      match fmt {
+        "toml" => {
+          fs::write(&p, config::DEFAULT_CONFIG)?;
+          println!("wrote config to {}", p.display());
+        }
+        f => self.cfg.write(&p, Some(f))?,
+      }
+    }
+    let data = &self.cfg.data_dir;
+    if !data.exists() && self.act(format!("create data directory {}", data.display())) {
+      fs::create_dir_all(&data)?;
+      println!("created data directory {}", data.display());
+    }
+    Ok(())
+  }
+
+  /// Check a config file, printing each issue found
+  pub fn check_cfg(&'a self, opt: &ArgMatches) -> Result<()> {
+    let p: PathBuf = match opt.value_of("path").or_else(|| opt.value_of("config")) {
+      Some(p) => p.into(),
+      None => config::default_path().ok_or("unable to determine the config directory")?,
+    };
+    if !p.is_file() {
+      return Err(format!("config file not found: {}", p.display()).into());
+    }
+    let issues = Config::check(&p)?;
+    if issues.is_empty() {
+      println!("config ok");
+      return Ok(());
+    }
+    for (field, reason) in issues.iter() {
+      if field.is_empty() {
+        eprintln!("{}: {}", p.display(), reason);
+      } else {
+        eprintln!("{}: {}: {}", p.display(), field, reason);
       }
-    } else if self.cli.is_present("force") {
-      //  TODO 2021-11-04: overwrite existing config : requires --auto prompt
+    }
+    Err(format!("{} invalid field(s) in {}", issues.len(), p.display()).into())
+  }
+
+  /// Print the resolved configuration, annotating each value with its
+  /// source
+  pub fn env(&'a self, opt: &ArgMatches) -> Result<()> {
+    let vals = Config::explain(opt)?;
+    if opt.is_present("json") {
+      let vals: Vec<_> = vals
+        .iter()
+        .map(|(k, v, src)| serde_json::json!({"key": k, "value": v, "source": src}))
+        .collect();
+      println!("{}", serde_json::to_string_pretty(&vals)?);
+      return Ok(());
+    }
+    let mut table = "";
+    for (k, v, src) in vals.iter() {
+      let (parent, key) = match k.rsplit_once('.') {
+        Some((p, k)) => (p, k),
+        None => ("", k.as_str()),
+      };
+      if parent != table {
+        println!("\n[{}]", parent);
+        table = parent;
+      }
+      println!("{} = {} # {}", key, v, src);
+    }
+    Ok(())
+  }
+
+  /// Print the last lines of the configured log file, with `-f`
+  /// following it as it grows. In follow mode a missing file is
+  /// waited for, and a truncated or rotated file is read from the
+  /// start.
+  pub async fn logs(&'a self, opt: &ArgMatches) -> Result<()> {
+    let path = &self
+      .cfg
+      .log
+      .live_file()
+      .ok_or("no log file is configured, set log.file or --log-file")?;
+    let n: usize = opt.value_of("lines").unwrap_or("10").parse()?;
+    let follow = opt.is_present("follow");
+    let poll = Duration::from_millis(250);
+    if !path.exists() {
+      if !follow {
+        return Err(format!("log file {} doesn't exist yet", path.display()).into());
+      }
+      eprintln!("waiting for {} to be created", path.display());
+      while !path.exists() {
+        tokio::time::sleep(poll).await;
+      }
+    }
+    let (lines, mut pos) = tail(path, n)?;
+    let mut out = std::io::stdout();
+    out.write_all(String::from_utf8_lossy(&lines).as_bytes())?;
+    out.flush()?;
+    if !follow {
+      return Ok(());
+    }
+    loop {
+      tokio::time::sleep(poll).await;
+      let len = match fs::metadata(path) {
+        Ok(m) => m.len(),
+        // rotated away, wait for the new file
+        Err(_) => continue,
+      };
+      if len < pos {
+        pos = 0;
+      }
+      if len > pos {
+        let mut f = File::open(path)?;
+        f.seek(SeekFrom::Start(pos))?;
+        pos += std::io::copy(&mut f, &mut out)?;
+        out.flush()?;
+      }
+    }
+  }
+
+  /// Diagnose the environment, printing a pass/warn/fail line for each
+  /// check. Returns an error if any check fails.
+  pub fn doctor(&'a self, opt: &ArgMatches) -> Result<()> {
+    let mut checks: Vec<(&str, &str, String)> = vec![];
+    // config file
+    checks.push(match Config::locate(opt) {
+      Ok(Some(p)) => match Config::check(&p) {
+        Ok(i) if i.is_empty() => ("pass", "config", format!("{} is valid", p.display())),
+        Ok(i) => (
+          "fail",
+          "config",
+          format!(
+            "{} has {} invalid field(s), see 'shc config check'",
+            p.display(),
+            i.len()
+          ),
+        ),
+        Err(e) => ("fail", "config", e.to_string()),
+      },
+      Ok(None) => (
+        "warn",
+        "config",
+        "no config file found, using defaults".to_string(),
+      ),
+      Err(e) => ("fail", "config", e.to_string()),
+    });
+    // data directory
+    let d = &self.cfg.data_dir;
+    checks.push(if !d.is_dir() {
+      (
+        "fail",
+        "data dir",
+        format!("{} does not exist, run 'shc init'", d.display()),
+      )
+    } else {
+      let probe = d.join(".shc-doctor");
+      match File::create(&probe).and_then(|_| remove_file(&probe)) {
+        Ok(_) => ("pass", "data dir", format!("{} is writable", d.display())),
+        Err(e) => (
+          "fail",
+          "data dir",
+          format!("{} is not writable: {}", d.display(), e),
+        ),
+      }
+    });
+    // server socket
+    let srv = &self.cfg.server;
+    checks.push(match (srv.bind_fd, &srv.socket) {
+      (Some(fd), _) => ("pass", "socket", format!("using inherited fd {}", fd)),
+      (None, Some(s)) => match s.parent() {
+        Some(d) if d.is_dir() => ("pass", "socket", format!("{} can be created", s.display())),
+        _ => (
+          "fail",
+          "socket",
+          format!("parent directory of {} does not exist", s.display()),
+        ),
+      },
+      (None, None) => match srv.addrs() {
+        Ok(addrs) => {
+          // bind them all at once, like the server
+          let bound: std::io::Result<Vec<_>> =
+            addrs.iter().map(std::net::TcpListener::bind).collect();
+          let addrs: Vec<String> = addrs.iter().map(|a| a.to_string()).collect();
+          match bound {
+            Ok(_) => ("pass", "port", format!("{} is bindable", addrs.join(", "))),
+            Err(e) => (
+              "fail",
+              "port",
+              format!("unable to bind {}: {}", addrs.join(", "), e),
+            ),
+          }
+        }
+        Err(e) => ("fail", "port", e.to_string()),
+      },
+    });
+    // hg is only needed at build time
+    let hg = env::var_os("PATH")
+      .map(|p| env::split_paths(&p).any(|d| d.join("hg").is_file() || d.join("hg.exe").is_file()))
+      .unwrap_or(false);
+    checks.push(if hg {
+      ("pass", "hg", "found on PATH".to_string())
     } else {
-      error!("{} already exists, use -f to override", p.display());
+      (
+        "warn",
+        "hg",
+        "not found on PATH, builds will lack a commit hash".to_string(),
+      )
+    });
+    for (status, name, msg) in checks.iter() {
+      println!("{:<5} {}: {}", status, name, msg);
+    }
+    let failed = checks.iter().filter(|c| c.0 == "fail").count();
+    if failed > 0 {
+      return Err(format!("{} check(s) failed", failed).into());
     }
     Ok(())
   }
 
-  /// Initialize the database
+  /// Initialize the database, replacing any existing one
   pub fn init_db(&self) -> Result<(), DbErr> {
     let db_path: PathBuf = self.cfg.path.clone().join("data/db");
-    std::fs::remove_dir_all(&db_path)?;
-    Registry::new(&db_path)?;
+    if self.act(format!(
+      "remove and recreate the database {}",
+      db_path.display()
+    )) {
+      std::fs::remove_dir_all(&db_path)?;
+      Registry::new(&db_path)?;
+    }
     Ok(())
   }
 
@@ -302,8 +668,21 @@ impl<'a> App<'a> {
   /// Clean up shed resources
   pub async fn clean(&'a self) -> Result<()> {
     match self.cli.value_of("input") {
-      Some("cfg") => remove_file("~/.config/shed.cfg")?,
-      Some("log") => remove_file(self.cfg.path.join("data/log/shed.log"))?,
+      Some("cfg") => {
+        if self.act("remove ~/.config/shed.cfg".to_string()) {
+          remove_file("~/.config/shed.cfg")?
+        }
+      }
+      Some("log") => {
+        let log = self
+          .cfg
+          .log
+          .live_file()
+          .ok_or("no log file is configured, set log.file or --log-file")?;
+        if self.act(format!("remove {}", log.display())) {
+          remove_file(log)?
+        }
+      }
       _ => {
         for i in self.cfg.src.iter() {
           println!("not actually removing {}, silly", i.name);
@@ -314,8 +693,9 @@ impl<'a> App<'a> {
   }
 
   /// Start an external server
-  pub async fn serve(&'a self) -> Result<()> {
-    match self.cli.value_of("engine") {
+  pub async fn serve(&'a self, opt: &ArgMatches) -> Result<()> {
+    println!("starting server...");
+    match opt.value_of("engine") {
       Some("hg") => {
         hgweb(&self.cfg.hg).await?;
         Ok(())
@@ -324,6 +704,156 @@ impl<'a> App<'a> {
       Some(_) | None => Ok(error!("unrecognized server type!")),
     }
   }
+
+  /// Start the shs server on a free local port, check its health
+  /// endpoint, and shut it down again. Returns an error if the server
+  /// fails to start or responds incorrectly.
+  pub async fn selftest(&'a self) -> Result<()> {
+    // only the HTTP settings are kept, it always listens on an
+    // ephemeral local port
+    let cfg = server::ServerConfig {
+      bind: vec!["127.0.0.1".to_string()],
+      port: 0,
+      socket: None,
+      bind_fd: None,
+      data_dir: None,
+      pid_file: None,
+      drain_period: 0,
+      ..self.cfg.server.clone()
+    };
+    let health_path = cfg.health_path.clone();
+    let start = Instant::now();
+    let srv = server::Server::bind(cfg).await?;
+    let addr = *srv
+      .local_addrs()?
+      .first()
+      .ok_or("selftest server isn't listening on TCP")?;
+    let uri: hyper::Uri = format!("http://{}{}", addr, health_path).parse()?;
+    let (tx, rx) = tokio::sync::oneshot::channel::<()>();
+    let srv = srv.run_until(async {
+      rx.await.ok();
+      Ok(())
+    });
+    let client = async {
+      let res = hyper::Client::new().get(uri.clone()).await;
+      let checked: Result<Duration> = async {
+        let res = res.map_err(|e| format!("health check on {} failed: {}", uri, e))?;
+        let elapsed = start.elapsed();
+        let status = res.status();
+        let body = hyper::body::to_bytes(res.into_body()).await?;
+        if status != hyper::StatusCode::OK {
+          return Err(format!("health check on {} returned {}", uri, status).into());
+        }
+        if body != format!("{}\n", version()) {
+          return Err(format!("health check on {} returned {:?}", uri, body).into());
+        }
+        Ok(elapsed)
+      }
+      .await;
+      let _ = tx.send(());
+      checked
+    };
+    let (srv, checked) = tokio::join!(srv, client);
+    srv?;
+    let elapsed = checked?;
+    println!(
+      "selftest ok: health check on port {} in {:.1?}, {:.1?} total",
+      addr.port(),
+      elapsed,
+      start.elapsed()
+    );
+    Ok(())
+  }
+
+  /// Run the shs server in this process until it is shut down, with
+  /// server args from the subcommand `opt`. A dry run only reports the
+  /// files the server would create.
+  pub async fn run_server(&'a self, opt: &ArgMatches) -> Result<()> {
+    let mut cfg = self.cfg.server.clone();
+    cfg.apply(opt)?;
+    if self.dry_run {
+      if let Some(d) = cfg.data_dir.as_ref().filter(|d| !d.is_dir()) {
+        self.act(format!("create data directory {}", d.display()));
+      }
+      if let Some(p) = &cfg.pid_file {
+        self.act(format!("write the server PID to {}", p.display()));
+      }
+      self.act("start shs".to_string());
+      return Ok(());
+    }
+    let (cli, opt) = (self.cli.clone(), opt.clone());
+    let reload = server::Reload::new(self.cfg.log.clone(), self.log.clone(), move || {
+      let mut cfg = Config::resolve(&cli)?;
+      cfg.server.apply(&opt)?;
+      Ok(cfg)
+    });
+    server::run(cfg, Some(reload)).await?;
+    Ok(())
+  }
+
+  /// Start the shs server. Unless `--foreground` is given, the server
+  /// is re-spawned as a detached child process which writes its PID
+  /// to `--pid-file` (default: `server.pid_file` or
+  /// `<data_dir>/shs.pid`).
+  pub async fn daemon(&'a self, opt: &ArgMatches) -> Result<()> {
+    if opt.is_present("foreground") {
+      return self.run_server(opt).await;
+    }
+    let pid_file: PathBuf = match (opt.value_of("pid_file"), &self.cfg.server.pid_file) {
+      (Some(p), _) => p.into(),
+      (None, Some(p)) => p.clone(),
+      (None, None) => self.cfg.data_dir.join("shs.pid"),
+    };
+    // the child would fail with its output discarded, so check first
+    if let Some(pid) = server::pid_file_owner(&pid_file) {
+      return Err(
+        format!(
+          "shs is already running (pid {}, {})",
+          pid,
+          pid_file.display()
+        )
+        .into(),
+      );
+    }
+    // re-run ourselves with the same args in the foreground
+    let mut cmd = Command::new(env::current_exe()?);
+    cmd.args(env::args_os().skip(1)).arg("--foreground");
+    if !opt.is_present("pid_file") {
+      cmd.arg("--pid-file").arg(&pid_file);
+    }
+    cmd
+      .stdin(Stdio::null())
+      .stdout(Stdio::null())
+      .stderr(Stdio::null());
+    #[cfg(unix)]
+    {
+      use std::os::unix::process::CommandExt;
+      // start a new session so the server has no controlling terminal
+      unsafe {
+        cmd.pre_exec(|| {
+          if libc::setsid() == -1 {
+            Err(std::io::Error::last_os_error())
+          } else {
+            Ok(())
+          }
+        });
+      }
+    }
+    if !self.act(format!(
+      "start shs and write its PID to {}",
+      pid_file.display()
+    )) {
+      return Ok(());
+    }
+    let child = cmd.spawn()?;
+    println!(
+      "started shs (pid {}), pid file: {}",
+      child.id(),
+      pid_file.display()
+    );
+    Ok(())
+  }
+
   /// Download a remote resource
   pub async fn dl(&self, t: &str, resource: &str) -> Result<(), NetErr> {
     let dst = self.cfg.path.join("stash/tmp/");
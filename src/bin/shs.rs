@@ -1,8 +1,15 @@
 /// bin/shs.rs --- shed-server
-use rlib::{logger::flexi, util::Result};
+///
+/// Equivalent to `shc serve`, kept for existing deployments.
+use rlib::util::Result;
+use shed::{build_server_cli, logger, server, Config, Reload};
 
 #[tokio::main]
 async fn main() -> Result<()> {
-  flexi("trace")?;
+  let cli = build_server_cli().get_matches();
+  let cfg = Config::resolve(&cli)?;
+  let log = logger::try_init(&cfg.log)?;
+  let reload = Reload::new(cfg.log.clone(), log, move || Config::resolve(&cli));
+  server::run(cfg.server, Some(reload)).await?;
   Ok(())
 }
@@ -1,25 +1,177 @@
 /// cli.rs --- shed client cli
-use rlib::util::cli::{App, AppSettings, Arg, ColorChoice};
+use rlib::util::cli::{
+  comp_gen::{generate_to, Bash, Fish, PowerShell, Zsh},
+  App, AppSettings, Arg, ColorChoice,
+};
+use std::{
+  io,
+  path::{Path, PathBuf},
+};
+
+/// Return the package version. This is DEMON_VERSION (which includes
+/// the Mercurial commit hash) when it was captured by build.rs, else
+/// the plain CARGO_PKG_VERSION.
+pub fn version() -> &'static str {
+  match option_env!("DEMON_VERSION") {
+    Some(v) if !v.is_empty() => v,
+    _ => env!("CARGO_PKG_VERSION"),
+  }
+}
+
+/// Shells supported by `shc completions`
+pub const SHELLS: [&str; 5] = ["bash", "zsh", "fish", "powershell", "elvish"];
+
+/// Args shared by all programs
+fn global_args() -> Vec<Arg<'static>> {
+  vec![
+    Arg::new("config")
+      .short('c')
+      .long("config")
+      .about("override configuration values")
+      .takes_value(true)
+      .global(true),
+    Arg::new("data_dir")
+      .long("data-dir")
+      .takes_value(true)
+      .about("directory for program state [default: $XDG_DATA_HOME/shed]")
+      .global(true),
+    Arg::new("log_level")
+      .long("log-level")
+      .takes_value(true)
+      .env("SHED_LOG")
+      .about("set the log level or per-module directives like 'info,hyper=warn' [default: info]")
+      .global(true),
+    Arg::new("verbose")
+      .short('v')
+      .long("verbose")
+      .multiple_occurrences(true)
+      .conflicts_with("quiet")
+      .about("increase verbosity, may be repeated (-v debug, -vv trace)")
+      .global(true),
+    Arg::new("quiet")
+      .short('q')
+      .long("quiet")
+      .about("only log errors")
+      .global(true),
+    Arg::new("log_format")
+      .long("log-format")
+      .takes_value(true)
+      .possible_values(&["text", "json"])
+      .about("set the log format [default: text]")
+      .global(true),
+    Arg::new("log_file")
+      .long("log-file")
+      .takes_value(true)
+      .about("log to a file, keeping only warnings and errors on stderr")
+      .global(true),
+    Arg::new("log_target")
+      .long("log-target")
+      .takes_value(true)
+      .possible_values(&["stderr", "file", "syslog"])
+      .about("where to log [default: file with --log-file, else stderr]")
+      .global(true),
+    Arg::new("log_strict")
+      .long("log-strict")
+      .about("exit if logging can't be set up instead of continuing without it")
+      .global(true),
+    Arg::new("log_rotate")
+      .long("log-rotate")
+      .takes_value(true)
+      .about("rotate the log file by size (e.g. 10MB) or 'daily'")
+      .global(true),
+    Arg::new("log_timestamp")
+      .long("log-timestamp")
+      .takes_value(true)
+      .possible_values(&["rfc3339", "epoch", "none"])
+      .about("set the log timestamp format, none omits it [default: rfc3339]")
+      .global(true),
+    Arg::new("color")
+      .long("color")
+      .takes_value(true)
+      .possible_values(&["auto", "always", "never"])
+      .about("color log output [default: auto]")
+      .global(true),
+  ]
+}
+
+/// Args for programs which run the shs server. The port only gets
+/// the short flag `-p` if `port_short` is set, since `shc serve`
+/// already uses it for packages.
+fn server_args(port_short: bool) -> Vec<Arg<'static>> {
+  let port = Arg::new("port")
+    .long("port")
+    .takes_value(true)
+    .validator(|p| p.parse::<u16>())
+    .about("TCP port to listen on [default: 8080]");
+  vec![
+    Arg::new("bind")
+      .long("bind")
+      .takes_value(true)
+      .multiple_occurrences(true)
+      .about("IP address to listen on, may be repeated [default: 127.0.0.1]"),
+    if port_short { port.short('p') } else { port },
+    Arg::new("health_path")
+      .long("health-path")
+      .takes_value(true)
+      .about("HTTP path of the health check endpoint [default: /health]"),
+    Arg::new("socket")
+      .long("socket")
+      .takes_value(true)
+      .conflicts_with_all(&["bind", "port"])
+      .about("listen on a Unix socket instead of TCP"),
+    Arg::new("metrics")
+      .long("metrics")
+      .about("serve Prometheus metrics"),
+    Arg::new("metrics_path")
+      .long("metrics-path")
+      .takes_value(true)
+      .about("HTTP path of the metrics endpoint [default: /metrics]"),
+    Arg::new("max_connections")
+      .long("max-connections")
+      .takes_value(true)
+      .validator(|m| m.parse::<usize>())
+      .about("maximum number of concurrent connections, 0 is unlimited [default: 1024]"),
+    Arg::new("drain_period")
+      .long("drain-period")
+      .takes_value(true)
+      .validator(|d| d.parse::<u64>())
+      .about("seconds to keep answering health checks with 503 on shutdown [default: 5]"),
+    Arg::new("shutdown_timeout")
+      .long("shutdown-timeout")
+      .takes_value(true)
+      .validator(|t| t.parse::<u64>())
+      .about("seconds to wait for connections to finish on shutdown [default: 30]"),
+    Arg::new("request_timeout")
+      .long("request-timeout")
+      .takes_value(true)
+      .validator(|t| t.parse::<u64>())
+      .about("seconds before a stalled request is closed, 0 is unlimited [default: 60]"),
+    Arg::new("bind_fd")
+      .long("bind-fd")
+      .takes_value(true)
+      .validator(|fd| fd.parse::<i32>())
+      .conflicts_with_all(&["bind", "port", "socket"])
+      .about("use an inherited listening socket, e.g. from systemd (Unix only)"),
+    Arg::new("pid_file")
+      .long("pid-file")
+      .takes_value(true)
+      .about("write the server PID to a file while running"),
+  ]
+}
+
 pub fn build_cli() -> App<'static> {
   App::new("shc")
+    .version(version())
     .author("ellis <ellis@rwest.io>")
     .about("shed multi-development tool")
     .setting(AppSettings::TrailingVarArg)
     .setting(AppSettings::ArgRequiredElseHelp)
     .color(ColorChoice::Auto)
+    .args(global_args())
     .arg(
-      Arg::new("config")
-        .short('c')
-        .long("config")
-        .about("override configuration values")
-        .takes_value(true)
-        .global(true),
-    )
-    .arg(
-      Arg::new("log_level")
-        .short('?')
-        .about("set the log level")
-        .multiple_occurrences(true)
+      Arg::new("dry_run")
+        .long("dry-run")
+        .about("print the changes commands would make without making them")
         .global(true),
     )
     .subcommands(vec![
@@ -28,16 +180,21 @@ pub fn build_cli() -> App<'static> {
         .arg(
           Arg::new("path")
             .takes_value(true)
-            .default_value("~/.config/shed.cfg"),
+            .about("config file path [default: $XDG_CONFIG_HOME/shed/config.toml]"),
+        )
+        .arg(
+          Arg::new("force")
+            .short('f')
+            .long("force")
+            .about("overwrite existing files"),
         )
-        .arg(Arg::new("force").short('f').long("force"))
         .arg(Arg::new("db").short('d').long("db"))
         .arg(
           Arg::new("fmt")
             .long("fmt")
             .takes_value(true)
-            .about("config format")
-            .possible_values(&["json", "ron", "bin"]),
+            .about("config format, which sets the file extension [default: toml]")
+            .possible_values(&["toml", "json", "ron"]),
         ),
       App::new("edit")
         .alias("e")
@@ -61,7 +218,7 @@ pub fn build_cli() -> App<'static> {
             .about("midi devices"),
         )
         .arg(Arg::new("weather").short('w').about("weather report"))
-        .arg(Arg::new("vc").short('v').about("show repo status"))
+        .arg(Arg::new("vc").long("vc").about("show repo status"))
         .arg(
           Arg::new("remote")
             .short('r')
@@ -99,19 +256,88 @@ pub fn build_cli() -> App<'static> {
         .about("commit changes to upstream")
         .arg(Arg::new("to").takes_value(true).about("parent to push to")),
       App::new("serve")
-        .about("network services")
+        .about("run the shs server, or another network backend")
         .arg(
           Arg::new("package")
             .takes_value(true)
             .multiple_values(true)
             .short('p')
+            .long("package")
             .about("specify packages to serve"),
         )
         .arg(
           Arg::new("engine")
             .takes_value(true)
             .possible_values(&["hg", "dm", "ftp"])
-            .about("network backend"),
+            .about("network backend [default: the shs server]"),
+        )
+        .args(server_args(false)),
+      App::new("config")
+        .about("manage configuration")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .subcommand(
+          App::new("check").about("validate a config file").arg(
+            Arg::new("path").takes_value(true).about(
+              "config file to check [default: --config or $XDG_CONFIG_HOME/shed/config.toml]",
+            ),
+          ),
+        )
+        .subcommand(App::new("default").about("print the default config to stdout")),
+      App::new("env")
+        .about("print the resolved configuration and where each value came from")
+        .arg(
+          Arg::new("json")
+            .long("json")
+            .about("print as JSON instead of TOML"),
+        ),
+      App::new("doctor").about("diagnose problems with the environment"),
+      App::new("selftest").about("start the server on a free port and check that it responds"),
+      App::new("logs")
+        .about("print the end of the log file")
+        .arg(
+          Arg::new("follow")
+            .short('f')
+            .long("follow")
+            .about("keep printing lines as they are written"),
+        )
+        .arg(
+          Arg::new("lines")
+            .short('n')
+            .long("lines")
+            .takes_value(true)
+            .validator(|n| n.parse::<usize>())
+            .about("number of lines to print [default: 10]"),
+        ),
+      App::new("version").about("print version information, with build metadata if --verbose"),
+      App::new("daemon")
+        .about("run the shs server in the background")
+        .arg(
+          Arg::new("foreground")
+            .long("foreground")
+            .about("don't detach from the controlling terminal"),
+        )
+        .args(server_args(true)),
+      App::new("completions")
+        .about("generate shell completions")
+        .arg(
+          Arg::new("shell")
+            .takes_value(true)
+            .required_unless_present("list")
+            .possible_values(&SHELLS)
+            .about("target shell"),
+        )
+        .arg(
+          Arg::new("list")
+            .long("list")
+            .conflicts_with_all(&["shell", "out_dir"])
+            .about("print the supported shells"),
+        )
+        .arg(
+          Arg::new("out_dir")
+            .short('o')
+            .long("out-dir")
+            .takes_value(true)
+            .about("write completions to a directory instead of stdout"),
         ),
       App::new("build")
         .alias("b")
@@ -126,3 +352,26 @@ pub fn build_cli() -> App<'static> {
         ),
     ])
 }
+
+pub fn build_server_cli() -> App<'static> {
+  App::new("shs")
+    .version(version())
+    .author("ellis <ellis@rwest.io>")
+    .about("shed HTTP service daemon")
+    .color(ColorChoice::Auto)
+    .args(global_args())
+    .args(server_args(true))
+}
+
+/// Write bash, zsh, fish, and powershell completions for `app`,
+/// invoked as `name`, to `dir`. Returns the paths of the generated
+/// files.
+pub fn generate_completions(app: &mut App, name: &str, dir: &Path) -> io::Result<Vec<PathBuf>> {
+  Ok(vec![
+    generate_to(Bash, app, name, dir)?,
+    generate_to(Zsh, app, name, dir)?,
+    // fish loads completions from '<name>.fish'
+    generate_to(Fish, app, name, dir)?,
+    generate_to(PowerShell, app, name, dir)?,
+  ])
+}
@@ -1,7 +1,7 @@
 /// config.rs --- shed configurations
 use std::{
   collections::HashMap,
-  fs,
+  env, fs,
   path::{Path, PathBuf},
 };
 
@@ -11,13 +11,25 @@ use rlib::{
     impl_config, ron::de::from_reader, Configure, HgwebConfig, MercurialConfig, NetworkConfig,
     Objective, PackageConfig, ProgramConfig, ProjectConfig, Result, UserConfig,
   },
+  util::{cli::ArgMatches, Result as UResult},
 };
 
-use serde::{Deserialize, Serialize};
+use serde::{de, de::DeserializeOwned, Deserialize, Deserializer, Serialize};
+
+use crate::{LogConfig, ServerConfig};
 
 /// Shed configuration type
+///
+/// A running shs server reloads its config on SIGHUP, but only
+/// `log.level` is applied at runtime. Other fields (like
+/// `server.bind`, `server.port`, and `data_dir`) require a restart.
+///
+/// Path fields expand environment variables written as `${VAR}`, or
+/// `${VAR:-default}` to fall back when VAR is unset.
 #[derive(Serialize, Deserialize, Debug)]
+#[serde(default)]
 pub struct Config {
+  #[serde(deserialize_with = "expand_path")]
   pub path: PathBuf, // the shed path on disk
   pub src: Vec<PackageConfig>,
   pub bin: Vec<ProgramConfig>,
@@ -25,6 +37,208 @@ pub struct Config {
   pub hg: MercurialConfig,
   pub lab: Vec<ProjectConfig>,
   pub usr: UserConfig,
+  #[serde(deserialize_with = "expand_path")]
+  pub data_dir: PathBuf, // program state, $XDG_DATA_HOME/shed
+  pub log: LogConfig,
+  pub server: ServerConfig,
+}
+
+/// The default config file written by `shc init`
+pub const DEFAULT_CONFIG: &str = r#"# shed configuration
+#
+# CLI flags override values set here, which override the built-in
+# defaults shown below.
+
+# the shed path on disk, $SHED when it was set at build time, else
+# ~/shed. paths may reference environment variables as ${VAR}, or
+# ${VAR:-default} when VAR may be unset, like "${HOME}/shed".
+# path = "~/shed"
+# directory for program state, created on startup
+# data_dir = "${HOME}/.local/share/shed"
+
+[log]
+# one of error, warn, info, debug, or trace, or per-module
+# directives like "info,shed=debug,hyper=warn"
+# level = "info"
+# one of text or json
+# format = "text"
+# log to this file instead of stderr
+# file = "/var/log/shed/shs.log"
+# one of stderr, file, or syslog. defaults to file when file is set,
+# else stderr.
+# target = "stderr"
+# rotate the log file by size (e.g. "10MB") or "daily". while
+# rotating, the live log is written to <stem>_rCURRENT.<ext> next to
+# file, and rotated logs are numbered like <stem>_r00000.<ext>.
+# rotate = "daily"
+# color stderr output: one of auto, always, or never. auto colors
+# only when stderr is a TTY and NO_COLOR is unset.
+# color = "auto"
+# timestamp format: one of rfc3339, epoch, or none. none is useful
+# under journald, which adds its own.
+# timestamp = "rfc3339"
+# exit if logging can't be set up, instead of continuing without it
+# strict = false
+
+[server]
+# IP address to listen on, or a list like ["0.0.0.0", "::"]
+# bind = "127.0.0.1"
+# TCP port to listen on, 0 picks an ephemeral port
+# port = 8080
+# HTTP path of the health check endpoint
+# health_path = "/health"
+# listen on a Unix socket instead of bind and port
+# socket = "/run/shed/shs.sock"
+# serve Prometheus metrics at metrics_path
+# metrics = false
+# metrics_path = "/metrics"
+# maximum number of concurrent connections, 0 is unlimited
+# max_connections = 1024
+# seconds to keep accepting connections once shutdown begins, with
+# the health check answering 503 so load balancers stop routing here
+# drain_period = 5
+# seconds to wait for open connections to finish on shutdown before
+# exiting anyway
+# shutdown_timeout = 30
+# seconds a request may take to be handled, or a client to send the
+# request headers, before its connection is closed, 0 is unlimited
+# request_timeout = 60
+# use an inherited listening socket instead of socket, bind, and
+# port, for systemd socket activation (unix only)
+# bind_fd = 3
+# write the server PID to this file while running
+# pid_file = "/run/shed/shs.pid"
+"#;
+
+/// Expand `${VAR}` and `${VAR:-default}` references in `s` from the
+/// process environment. Referencing an unset variable without a
+/// default is an error.
+pub fn expand_env(s: &str) -> std::result::Result<String, String> {
+  let mut out = String::with_capacity(s.len());
+  let mut rest = s;
+  while let Some(i) = rest.find("${") {
+    out.push_str(&rest[..i]);
+    let end = rest[i..]
+      .find('}')
+      .ok_or_else(|| format!("unterminated '${{' in '{}'", s))?;
+    let (name, default) = match rest[i + 2..i + end].split_once(":-") {
+      Some((n, d)) => (n, Some(d)),
+      None => (&rest[i + 2..i + end], None),
+    };
+    if name.is_empty() {
+      return Err(format!("empty variable name in '{}'", s));
+    }
+    match (env::var(name), default) {
+      (Ok(v), _) => out.push_str(&v),
+      (Err(_), Some(d)) => out.push_str(d),
+      (Err(_), None) => return Err(format!("undefined variable '{}' in '{}'", name, s)),
+    }
+    rest = &rest[i + end + 1..];
+  }
+  out.push_str(rest);
+  Ok(out)
+}
+
+/// Deserialize a path, expanding environment variables with
+/// `expand_env`
+pub(crate) fn expand_path<'de, D: Deserializer<'de>>(
+  d: D,
+) -> std::result::Result<PathBuf, D::Error> {
+  let s = String::deserialize(d)?;
+  expand_env(&s).map(PathBuf::from).map_err(de::Error::custom)
+}
+
+/// Like `expand_path`, for optional paths
+pub(crate) fn expand_opt_path<'de, D: Deserializer<'de>>(
+  d: D,
+) -> std::result::Result<Option<PathBuf>, D::Error> {
+  match Option::<String>::deserialize(d)? {
+    Some(s) => expand_env(&s)
+      .map(|s| Some(s.into()))
+      .map_err(de::Error::custom),
+    None => Ok(None),
+  }
+}
+
+/// Return `$XDG_<var>` or `$HOME/<home>` joined with 'shed'
+fn xdg_dir(var: &str, home: &str) -> Option<PathBuf> {
+  env::var_os(var)
+    .filter(|v| !v.is_empty())
+    .map(PathBuf::from)
+    .or_else(|| env::var_os("HOME").map(|h| Path::new(&h).join(home)))
+    .map(|p| p.join("shed"))
+}
+
+/// Return the default config file path,
+/// `$XDG_CONFIG_HOME/shed/config.toml`.
+pub fn default_path() -> Option<PathBuf> {
+  xdg_dir("XDG_CONFIG_HOME", ".config").map(|p| p.join("config.toml"))
+}
+
+/// Return the default data directory, `$XDG_DATA_HOME/shed`.
+pub fn default_data_dir() -> Option<PathBuf> {
+  xdg_dir("XDG_DATA_HOME", ".local/share")
+}
+
+/// Collect the leaves of `val` into `out` as '(key path, value)'
+/// pairs. The values of a table come before its sub-tables, so the
+/// result can be written back out as TOML in order.
+fn flatten(key: Vec<String>, val: toml::Value, out: &mut Vec<(Vec<String>, toml::Value)>) {
+  match val {
+    toml::Value::Table(t) if !t.is_empty() => {
+      let (tables, values): (Vec<_>, Vec<_>) = t
+        .into_iter()
+        .partition(|(_, v)| matches!(v, toml::Value::Table(t) if !t.is_empty()));
+      for (k, v) in values.into_iter().chain(tables) {
+        let mut key = key.clone();
+        key.push(k);
+        flatten(key, v, out);
+      }
+    }
+    v => out.push((key, v)),
+  }
+}
+
+/// Look up the value at `key` in `val`
+fn lookup<'a>(val: &'a toml::Value, key: &[String]) -> Option<&'a toml::Value> {
+  key.iter().try_fold(val, |v, k| v.get(k.as_str()))
+}
+
+/// A problem found while checking a config: the dotted path of the
+/// offending field and the reason it is invalid.
+pub type ConfigIssue = (String, String);
+
+/// Deserialize the value of the table field `name` one key at a time,
+/// so that each invalid key is reported as 'name.key'. Returns the
+/// value deserialized from the valid keys.
+fn check_table<T: DeserializeOwned>(
+  name: &str,
+  val: toml::Value,
+  issues: &mut Vec<ConfigIssue>,
+) -> Option<T> {
+  let table = match val {
+    toml::Value::Table(t) => t,
+    v => {
+      return v
+        .try_into()
+        .map_err(|e| issues.push((name.to_string(), e.to_string())))
+        .ok()
+    }
+  };
+  let mut valid = toml::value::Table::new();
+  for (k, v) in table {
+    let one: toml::value::Table = [(k.clone(), v.clone())].into_iter().collect();
+    match toml::Value::Table(one).try_into::<T>() {
+      Ok(_) => {
+        valid.insert(k, v);
+      }
+      Err(e) => issues.push((format!("{}.{}", name, k), e.to_string())),
+    }
+  }
+  toml::Value::Table(valid)
+    .try_into()
+    .map_err(|e| issues.push((name.to_string(), e.to_string())))
+    .ok()
 }
 
 impl Config {
@@ -37,20 +251,121 @@ impl Config {
     };
     let lab = vec![];
     let usr = UserConfig::default();
+    let path = PathBuf::from(option_env!("SHED").unwrap_or("~/shed"));
     Config {
-      path: PathBuf::from(option_env!("SHED").unwrap_or("~/shed")),
+      data_dir: default_data_dir().unwrap_or_else(|| path.join("data")),
+      path,
       src: vec![],
       bin: vec![],
       net: NetworkConfig::default(),
       hg,
       lab,
       usr,
+      log: LogConfig::default(),
+      server: ServerConfig::default(),
     }
   }
 
+  /// Return the config file to load for CLI args: the `--config`
+  /// path if given, else the first of `default_path()` (or the same
+  /// path with a `.json` or `.ron` extension) or the legacy
+  /// `$CFG/shed.cfg` which exists.
+  pub fn locate(cli: &ArgMatches) -> UResult<Option<PathBuf>> {
+    match cli.value_of("config") {
+      Some(p) => {
+        info!("custom cfg: {}", p);
+        if !Path::new(p).is_file() {
+          return Err(format!("config file not found: {}", p).into());
+        }
+        Ok(Some(p.into()))
+      }
+      None => {
+        let legacy = option_env!("CFG").map(|c| Path::new(c).join("shed.cfg"));
+        let default = default_path();
+        let paths = [
+          default.clone(),
+          default.as_ref().map(|p| p.with_extension("json")),
+          default.map(|p| p.with_extension("ron")),
+          legacy,
+        ];
+        Ok(paths.into_iter().flatten().find(|p| p.is_file()))
+      }
+    }
+  }
+
+  /// Resolve the configuration from CLI args. CLI flags override
+  /// values from the config file (see `locate`), which override the
+  /// built-in defaults. The result is checked with `ensure_valid`.
+  pub fn resolve(cli: &ArgMatches) -> UResult<Self> {
+    let mut cfg = match Config::locate(cli)? {
+      Some(p) => Config::load(p)?,
+      None => Config::new(),
+    };
+    if let Some(d) = cli.value_of("data_dir") {
+      cfg.data_dir = d.into();
+    }
+    cfg.log.apply(cli)?;
+    cfg.server.apply(cli)?;
+    cfg.server.data_dir = Some(cfg.data_dir.clone());
+    cfg.ensure_valid()?;
+    Ok(cfg)
+  }
+
+  /// Resolve the configuration like `resolve`, returning each value
+  /// with its dotted key and where it came from: 'default', 'file',
+  /// 'cli' (flags), or 'env' (env vars like SHED_LOG).
+  pub fn explain(cli: &ArgMatches) -> UResult<Vec<(String, toml::Value, &'static str)>> {
+    let path = Config::locate(cli)?;
+    let (file, raw) = match &path {
+      Some(p) => {
+        let file = Config::load(p)?;
+        let raw: toml::Value = if p.extension().and_then(|e| e.to_str()) == Some("toml") {
+          fs::read_to_string(p)?.parse()?
+        } else {
+          toml::Value::try_from(&file)?
+        };
+        (file, Some(raw))
+      }
+      None => (Config::new(), None),
+    };
+    let file = toml::Value::try_from(&file)?;
+    // a level from SHED_LOG counts as present without occurring on the
+    // command line, and -q and -v override it
+    let env_level = cli.is_present("log_level")
+      && cli.occurrences_of("log_level") == 0
+      && !cli.is_present("quiet")
+      && !cli.is_present("verbose");
+    let mut leaves = vec![];
+    flatten(
+      vec![],
+      toml::Value::try_from(&Config::resolve(cli)?)?,
+      &mut leaves,
+    );
+    Ok(
+      leaves
+        .into_iter()
+        .map(|(k, v)| {
+          let src = if lookup(&file, &k) != Some(&v) {
+            if env_level && k == ["log", "level"] {
+              "env"
+            } else {
+              "cli"
+            }
+          } else if raw.as_ref().and_then(|r| lookup(r, &k)).is_some() {
+            "file"
+          } else {
+            "default"
+          };
+          (k.join("."), v, src)
+        })
+        .collect(),
+    )
+  }
+
+  /// Write the config to the file at `path`, in RON format unless
+  /// `ext` is given.
   pub fn write<P: AsRef<Path>>(&self, path: P, ext: Option<&str>) -> Result<()> {
-    let path = path.as_ref();
-    let f_path = &path.join("shed.cfg");
+    let f_path = path.as_ref();
     let file = fs::File::create(f_path)?;
     match ext {
       Some(i) => match i {
@@ -68,20 +383,116 @@ impl Config {
     Ok(())
   }
 
-  pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
-    let f = fs::File::open(path)?;
-    let config: Config = match from_reader(f) {
-      Ok(x) => {
-        info!("loading config: {:?}", x);
-        x
-      }
-      Err(e) => {
-        error!("Failed to load config: {}", e);
-        std::process::exit(1);
-      }
+  /// Load a config file. Files with a `.toml` extension are parsed as
+  /// TOML, `.json` as JSON, and everything else as RON.
+  pub fn load<P: AsRef<Path>>(path: P) -> UResult<Self> {
+    let path = path.as_ref();
+    let config: Config = match path.extension().and_then(|e| e.to_str()) {
+      Some("toml") => toml::from_str(&fs::read_to_string(path)?)
+        .map_err(|e| format!("failed to load config {}: {}", path.display(), e))?,
+      Some("json") => serde_json::from_reader(fs::File::open(path)?)
+        .map_err(|e| format!("failed to load config {}: {}", path.display(), e))?,
+      _ => from_reader(fs::File::open(path)?)
+        .map_err(|e| format!("failed to load config {}: {}", path.display(), e))?,
     };
+    info!("loading config: {:?}", config);
     Ok(config)
   }
+
+  /// Check the config file at `path` without loading it for use,
+  /// returning every issue found. TOML files are checked field by
+  /// field, including the fields of the `log` and `server` tables.
+  /// Other formats stop at the first deserialization error.
+  pub fn check<P: AsRef<Path>>(path: P) -> UResult<Vec<ConfigIssue>> {
+    let path = path.as_ref();
+    if path.extension().and_then(|e| e.to_str()) != Some("toml") {
+      return Ok(match Config::load(path) {
+        Ok(cfg) => cfg.validate(),
+        Err(e) => vec![(String::new(), e.to_string())],
+      });
+    }
+    let table = match fs::read_to_string(path)?.parse::<toml::Value>() {
+      Ok(toml::Value::Table(t)) => t,
+      Ok(_) => return Ok(vec![(String::new(), "expected a table".to_string())]),
+      Err(e) => return Ok(vec![(String::new(), e.to_string())]),
+    };
+    let mut cfg = Config::new();
+    let mut issues = vec![];
+    for (k, v) in table {
+      let res = match k.as_str() {
+        "path" => expand_path(v).map(|x| cfg.path = x),
+        "data_dir" => expand_path(v).map(|x| cfg.data_dir = x),
+        "src" => v.try_into().map(|x| cfg.src = x),
+        "bin" => v.try_into().map(|x| cfg.bin = x),
+        "net" => v.try_into().map(|x| cfg.net = x),
+        "hg" => v.try_into().map(|x| cfg.hg = x),
+        "lab" => v.try_into().map(|x| cfg.lab = x),
+        "usr" => v.try_into().map(|x| cfg.usr = x),
+        "log" => {
+          if let Some(x) = check_table(&k, v, &mut issues) {
+            cfg.log = x;
+          }
+          continue;
+        }
+        "server" => {
+          if let Some(x) = check_table(&k, v, &mut issues) {
+            cfg.server = x;
+          }
+          continue;
+        }
+        _ => {
+          issues.push((k, "unknown field".to_string()));
+          continue;
+        }
+      };
+      if let Err(e) = res {
+        issues.push((k, e.to_string()));
+      }
+    }
+    issues.extend(cfg.validate());
+    Ok(issues)
+  }
+
+  /// Check the constraints on config values which aren't expressed by
+  /// their types.
+  pub fn validate(&self) -> Vec<ConfigIssue> {
+    let mut issues = self.log.validate();
+    issues.extend(self.server.validate());
+    issues
+  }
+
+  /// Like `validate`, returning an error which lists every issue
+  pub fn ensure_valid(&self) -> UResult<()> {
+    let issues = self.validate();
+    if issues.is_empty() {
+      return Ok(());
+    }
+    let issues: Vec<String> = issues
+      .iter()
+      .map(|(k, e)| format!("{}: {}", k, e))
+      .collect();
+    Err(format!("invalid config: {}", issues.join(", ")).into())
+  }
+}
+
+impl Default for Config {
+  fn default() -> Self {
+    Config::new()
+  }
 }
 
 impl_config!(Config);
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  #[test]
+  fn test_expand_env() {
+    env::set_var("SHED_TEST_EXPAND", "/srv");
+    assert_eq!(expand_env("${SHED_TEST_EXPAND}/shed").unwrap(), "/srv/shed");
+    assert_eq!(expand_env("${SHED_TEST_UNSET:-/tmp}/x").unwrap(), "/tmp/x");
+    assert_eq!(expand_env("$HOME/plain").unwrap(), "$HOME/plain");
+    assert!(expand_env("${SHED_TEST_UNSET}").is_err());
+    assert!(expand_env("${SHED_TEST_EXPAND").is_err());
+  }
+}
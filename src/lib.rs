@@ -5,7 +5,9 @@ configs, docs, secrets, and data. It is intended to be an
 `org-specific` structure which is maintained internally.
 
 This library is included as a convenience and is implemented by the
-individual programs in the `bin` folder.
+individual programs in the `bin` folder, which are thin wrappers
+around it. The CLI builders, config types, and server entrypoint are
+re-exported here so they can be embedded in other programs.
 */
 //      _              _
 //     | |            | |
@@ -17,13 +19,31 @@ individual programs in the `bin` folder.
 // client
 mod app;
 mod cli;
-pub use self::{app::App, cli::build_cli, config::Config};
+pub use self::{
+  app::App,
+  cli::{build_cli, build_server_cli, generate_completions, version},
+  config::{Config, ConfigIssue},
+  logger::LogConfig,
+  server::{run, Reload, Server, ServerConfig, ServerError},
+};
 
 // common
 mod coding;
-mod config;
+pub mod config;
+pub mod logger;
 
 // services
+pub mod server;
 mod web;
 
 pub const MTU: usize = u16::MAX as usize;
+
+/// Build metadata captured by build.rs
+pub const BUILD_INFO: [(&str, &str); 6] = [
+  ("commit", env!("SHED_COMMIT")),
+  ("rustc", env!("SHED_RUSTC_VERSION")),
+  ("target", env!("SHED_TARGET")),
+  ("profile", env!("SHED_PROFILE")),
+  ("built", env!("SHED_BUILD_TIMESTAMP")),
+  ("host", env!("SHED_BUILD_HOST")),
+];
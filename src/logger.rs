@@ -0,0 +1,486 @@
+//! logger.rs --- shed logging
+/*!
+Logger setup shared by the `shed` programs, driven by a 'LogConfig'.
+*/
+use crate::config::expand_opt_path;
+use flexi_logger::{
+  style, writers::LogWriter, Age, Cleanup, Criterion, DeferredNow, Duplicate, FileSpec,
+  FormatFunction, LogSpecification, Logger, LoggerHandle, Naming, Record,
+};
+use rlib::{
+  logger::log::{warn, LevelFilter},
+  util::{cli::ArgMatches, Result},
+};
+use serde::{Deserialize, Serialize};
+use std::{
+  env, fs,
+  io::{self, Write},
+  path::PathBuf,
+  sync::atomic::{AtomicU8, Ordering},
+};
+
+/// Logging configuration type
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct LogConfig {
+  /// one of error, warn, info, debug, or trace, or a directive spec
+  /// with per-module levels like 'info,shed=debug,hyper=warn'.
+  /// Reloaded by shs on SIGHUP.
+  pub level: String,
+  /// one of text or json
+  pub format: String,
+  /// log to this file instead of stderr
+  #[serde(deserialize_with = "expand_opt_path")]
+  pub file: Option<PathBuf>,
+  /// rotate `file` by size (e.g. '10MB') or 'daily'. The live log is
+  /// then written to `live_file()`.
+  pub rotate: Option<String>,
+  /// color stderr output: one of auto, always, or never
+  pub color: String,
+  /// timestamp format: one of rfc3339, epoch, or none
+  pub timestamp: String,
+  /// where to log: one of stderr, file, or syslog. Defaults to file
+  /// when `file` is set, else stderr.
+  pub target: Option<String>,
+  /// exit if the logger can't be initialized, instead of continuing
+  /// without logging
+  pub strict: bool,
+}
+
+impl Default for LogConfig {
+  fn default() -> Self {
+    LogConfig {
+      level: "info".to_string(),
+      format: "text".to_string(),
+      file: None,
+      rotate: None,
+      color: "auto".to_string(),
+      timestamp: "rfc3339".to_string(),
+      target: None,
+      strict: false,
+    }
+  }
+}
+
+/// Valid log level names
+pub const LEVELS: [&str; 5] = ["error", "warn", "info", "debug", "trace"];
+
+impl LogConfig {
+  /// Override values with any logging args present in `cli`. `-q` and
+  /// `-v` can't be combined with `--log-level`, but take precedence
+  /// over `SHED_LOG`.
+  pub fn apply(&mut self, cli: &ArgMatches) -> Result<()> {
+    // not a clap conflict, which would also reject a level from
+    // SHED_LOG
+    let verbosity = cli.is_present("quiet") || cli.is_present("verbose");
+    if verbosity && cli.occurrences_of("log_level") > 0 {
+      return Err("--log-level can't be used with -q or -v".into());
+    }
+    if let Some(l) = cli.value_of("log_level") {
+      self.level = l.to_string();
+    }
+    if cli.is_present("quiet") {
+      self.level = "error".to_string();
+    }
+    match cli.occurrences_of("verbose") {
+      0 => (),
+      1 => self.level = "debug".to_string(),
+      _ => self.level = "trace".to_string(),
+    }
+    if let Some(f) = cli.value_of("log_format") {
+      self.format = f.to_string();
+    }
+    if let Some(f) = cli.value_of("log_file") {
+      self.file = Some(f.into());
+    }
+    if let Some(r) = cli.value_of("log_rotate") {
+      self.rotate = Some(r.to_string());
+    }
+    if let Some(c) = cli.value_of("color") {
+      self.color = c.to_string();
+    }
+    if let Some(t) = cli.value_of("log_timestamp") {
+      self.timestamp = t.to_string();
+    }
+    if let Some(t) = cli.value_of("log_target") {
+      self.target = Some(t.to_string());
+    }
+    if cli.is_present("log_strict") {
+      self.strict = true;
+    }
+    Ok(())
+  }
+
+  /// Return the log target in effect
+  pub fn target(&self) -> &str {
+    match &self.target {
+      Some(t) => t,
+      None if self.file.is_some() => "file",
+      None => "stderr",
+    }
+  }
+
+  /// Return the file currently being logged to, if any. While
+  /// rotating, the live file is '<stem>_rCURRENT.<ext>' next to
+  /// `file`, which is renamed with a number when it is rotated.
+  pub fn live_file(&self) -> Option<PathBuf> {
+    let path = self.file.as_ref()?;
+    if self.rotate.is_none() {
+      return Some(path.clone());
+    }
+    let stem = path.file_stem()?.to_string_lossy();
+    Some(path.with_file_name(match path.extension() {
+      Some(ext) => format!("{}_rCURRENT.{}", stem, ext.to_string_lossy()),
+      None => format!("{}_rCURRENT", stem),
+    }))
+  }
+
+  /// Check the values of this config, returning any issues as
+  /// '(field, reason)' pairs.
+  pub fn validate(&self) -> Vec<(String, String)> {
+    let mut issues = vec![];
+    if let Err(e) = parse_spec(&self.level) {
+      issues.push(("log.level".to_string(), e.to_string()));
+    }
+    if !["text", "json"].contains(&self.format.as_str()) {
+      issues.push((
+        "log.format".to_string(),
+        format!("unknown format '{}', expected text or json", self.format),
+      ));
+    }
+    if let Some(r) = &self.rotate {
+      if let Err(e) = rotate_criterion(r) {
+        issues.push(("log.rotate".to_string(), e.to_string()));
+      } else if self.file.is_none() {
+        issues.push((
+          "log.rotate".to_string(),
+          "rotating requires log.file to be set".to_string(),
+        ));
+      }
+    }
+    if !["auto", "always", "never"].contains(&self.color.as_str()) {
+      issues.push((
+        "log.color".to_string(),
+        format!(
+          "unknown color '{}', expected auto, always, or never",
+          self.color
+        ),
+      ));
+    }
+    match self.target() {
+      "stderr" | "syslog" => (),
+      "file" if self.file.is_none() => issues.push((
+        "log.target".to_string(),
+        "target 'file' requires log.file to be set".to_string(),
+      )),
+      "file" => (),
+      t => issues.push((
+        "log.target".to_string(),
+        format!("unknown target '{}', expected stderr, file, or syslog", t),
+      )),
+    }
+    if Timestamp::parse(&self.timestamp).is_none() {
+      issues.push((
+        "log.timestamp".to_string(),
+        format!(
+          "unknown timestamp '{}', expected rfc3339, epoch, or none",
+          self.timestamp
+        ),
+      ));
+    }
+    issues
+  }
+}
+
+/// Timestamp format of log lines
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[repr(u8)]
+enum Timestamp {
+  Rfc3339,
+  /// seconds since the Unix epoch, with microseconds
+  Epoch,
+  Omit,
+}
+
+impl Timestamp {
+  fn parse(s: &str) -> Option<Self> {
+    match s {
+      "rfc3339" => Some(Timestamp::Rfc3339),
+      "epoch" => Some(Timestamp::Epoch),
+      "none" => Some(Timestamp::Omit),
+      _ => None,
+    }
+  }
+}
+
+/// The timestamp format used by the format functions. flexi calls them
+/// through plain function pointers, so this is set once by `init`.
+static TIMESTAMP: AtomicU8 = AtomicU8::new(Timestamp::Rfc3339 as u8);
+
+/// Return the timestamp for a log line, or None if timestamps are
+/// disabled
+fn timestamp(now: &mut DeferredNow) -> Option<String> {
+  match TIMESTAMP.load(Ordering::Relaxed) {
+    t if t == Timestamp::Epoch as u8 => {
+      let t = now.now();
+      Some(format!(
+        "{}.{:06}",
+        t.timestamp(),
+        t.timestamp_subsec_micros()
+      ))
+    }
+    t if t == Timestamp::Omit as u8 => None,
+    _ => Some(now.format_rfc3339()),
+  }
+}
+
+/// Write a log record as 'TIMESTAMP LEVEL [module] message'
+pub fn text_format(w: &mut dyn Write, now: &mut DeferredNow, record: &Record) -> io::Result<()> {
+  if let Some(t) = timestamp(now) {
+    write!(w, "{} ", t)?;
+  }
+  write!(
+    w,
+    "{} [{}] {}",
+    record.level(),
+    record.module_path().unwrap_or("<unnamed>"),
+    record.args()
+  )
+}
+
+/// Like `text_format`, with the level and message colored by level
+pub fn colored_text_format(
+  w: &mut dyn Write,
+  now: &mut DeferredNow,
+  record: &Record,
+) -> io::Result<()> {
+  if let Some(t) = timestamp(now) {
+    write!(w, "{} ", t)?;
+  }
+  let level = record.level();
+  write!(
+    w,
+    "{} [{}] {}",
+    style(level).paint(level.to_string()),
+    record.module_path().unwrap_or("<unnamed>"),
+    style(level).paint(record.args().to_string())
+  )
+}
+
+/// Parse a log level or directive spec like 'info,shed=debug'. A
+/// bare level name sets the default level for all modules.
+pub fn parse_spec(spec: &str) -> Result<LogSpecification> {
+  let err = |reason: String| {
+    format!(
+      "invalid log directive '{}': {}, expected a level ({}) or 'module=level' pairs",
+      spec,
+      reason,
+      LEVELS.join(", ")
+    )
+  };
+  // flexi reads a bare word which isn't a level as a module name, so
+  // a typo like 'degub' would silently enable trace for 'degub'
+  let directives = spec.split('/').next().unwrap_or_default();
+  for d in directives
+    .split(',')
+    .map(str::trim)
+    .filter(|d| !d.is_empty())
+  {
+    if !d.contains('=') && !LEVELS.contains(&d.to_ascii_lowercase().as_str()) && d != "off" {
+      return Err(err(format!("unknown level '{}'", d)).into());
+    }
+  }
+  LogSpecification::parse(spec).map_err(|e| err(e.to_string()).into())
+}
+
+/// Write a log record as a single line JSON object
+pub fn json_format(w: &mut dyn Write, now: &mut DeferredNow, record: &Record) -> io::Result<()> {
+  let mut obj = serde_json::json!({
+    "level": record.level().as_str(),
+    "module": record.module_path().unwrap_or("<unnamed>"),
+    "message": record.args().to_string(),
+  });
+  if let Some(t) = timestamp(now) {
+    obj["timestamp"] = t.into();
+  }
+  write!(w, "{}", obj)
+}
+
+/// Parse a human-readable size like '10MB' or '512k' into bytes
+pub fn parse_size(s: &str) -> Result<u64> {
+  let s = s.trim();
+  let i = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+  let (n, unit) = s.split_at(i);
+  let n: u64 = n.parse().map_err(|_| format!("invalid size '{}'", s))?;
+  let m = match unit.trim().to_ascii_lowercase().as_str() {
+    "" | "b" => 1,
+    "k" | "kb" | "kib" => 1 << 10,
+    "m" | "mb" | "mib" => 1 << 20,
+    "g" | "gb" | "gib" => 1 << 30,
+    _ => return Err(format!("invalid size unit in '{}'", s).into()),
+  };
+  n.checked_mul(m)
+    .ok_or_else(|| format!("size '{}' is too large", s).into())
+}
+
+/// Parse a '--log-rotate' value into a rotation `Criterion`
+fn rotate_criterion(s: &str) -> Result<Criterion> {
+  match s {
+    "daily" => Ok(Criterion::Age(Age::Day)),
+    s => Ok(Criterion::Size(parse_size(s)?)),
+  }
+}
+
+/// Return true if stderr output should be colored for `color`. With
+/// 'auto' this is only when stderr is a TTY and `NO_COLOR` is unset.
+fn use_color(color: &str) -> bool {
+  match color {
+    "always" => true,
+    "never" => false,
+    _ => env::var_os("NO_COLOR").is_none() && atty::is(atty::Stream::Stderr),
+  }
+}
+
+/// Connect to the local syslog daemon. Records are written in RFC
+/// 5424 format, with the name of the running program (like shs) and
+/// its PID.
+#[cfg(unix)]
+fn syslog_writer() -> io::Result<Box<dyn LogWriter>> {
+  use flexi_logger::writers::{Syslog, SyslogFacility, SyslogWriter};
+  // Linux, then macOS
+  let syslog =
+    Syslog::try_datagram("/dev/log").or_else(|_| Syslog::try_datagram("/var/run/syslog"))?;
+  let name = env::current_exe()
+    .ok()
+    .and_then(|p| Some(p.file_stem()?.to_string_lossy().into_owned()))
+    .unwrap_or_else(|| "shed".to_string());
+  let w: Box<dyn LogWriter> = SyslogWriter::try_new(
+    SyslogFacility::SystemDaemons,
+    None,
+    LevelFilter::Trace,
+    name,
+    syslog,
+  )?;
+  Ok(w)
+}
+
+#[cfg(not(unix))]
+fn syslog_writer() -> io::Result<Box<dyn LogWriter>> {
+  Err(io::Error::new(
+    io::ErrorKind::Unsupported,
+    "syslog is only supported on Unix",
+  ))
+}
+
+/// Initialize the global logger from `cfg`. The returned handle must
+/// be kept alive for as long as logging is needed. When syslog is the
+/// target but unreachable, stderr is used instead with a warning.
+pub fn init(cfg: &LogConfig) -> Result<LoggerHandle> {
+  let ts = Timestamp::parse(&cfg.timestamp)
+    .ok_or_else(|| format!("unknown log timestamp '{}'", cfg.timestamp))?;
+  TIMESTAMP.store(ts as u8, Ordering::Relaxed);
+  let (fmt, stderr_fmt): (FormatFunction, FormatFunction) = match cfg.format.as_str() {
+    "text" if use_color(&cfg.color) => (text_format, colored_text_format),
+    "text" => (text_format, text_format),
+    "json" => (json_format, json_format),
+    f => return Err(format!("unknown log format '{}'", f).into()),
+  };
+  let mut logger = Logger::with(parse_spec(&cfg.level)?)
+    .format_for_files(fmt)
+    .format_for_stderr(stderr_fmt);
+  let mut fallback = None;
+  match cfg.target() {
+    "stderr" => (),
+    "syslog" => match syslog_writer() {
+      Ok(w) => logger = logger.log_to_writer(w),
+      Err(e) => fallback = Some(e),
+    },
+    "file" => {
+      let path = cfg
+        .file
+        .as_ref()
+        .ok_or("log target 'file' requires log.file")?;
+      let mut spec = FileSpec::default().suppress_timestamp();
+      if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+        fs::create_dir_all(dir)?;
+        spec = spec.directory(dir);
+      }
+      if let Some(stem) = path.file_stem().and_then(|s| s.to_str()) {
+        spec = spec.basename(stem);
+      }
+      // flexi adds a '.log' suffix unless told otherwise
+      spec = spec.o_suffix(path.extension().and_then(|s| s.to_str()));
+      // keep the logs of previous runs instead of truncating them
+      logger = logger
+        .log_to_file(spec)
+        .append()
+        .duplicate_to_stderr(Duplicate::Warn);
+      if let Some(r) = &cfg.rotate {
+        logger = logger.rotate(rotate_criterion(r)?, Naming::Numbers, Cleanup::Never);
+      }
+    }
+    t => return Err(format!("unknown log target '{}'", t).into()),
+  }
+  let handle = logger.start()?;
+  if let Some(e) = fallback {
+    warn!("syslog is unreachable ({}), logging to stderr", e);
+  }
+  Ok(handle)
+}
+
+/// Initialize the global logger like `init`. Invalid settings are
+/// always an error, but unless `cfg.strict` is set a failure to set
+/// up the output (like an unwritable file) is printed to stderr and
+/// the program continues without logging.
+pub fn try_init(cfg: &LogConfig) -> Result<Option<LoggerHandle>> {
+  let issues = cfg.validate();
+  if !issues.is_empty() {
+    let issues: Vec<String> = issues
+      .iter()
+      .map(|(k, e)| format!("{}: {}", k, e))
+      .collect();
+    return Err(format!("invalid logging config: {}", issues.join(", ")).into());
+  }
+  match init(cfg) {
+    Ok(h) => Ok(Some(h)),
+    Err(e) if cfg.strict => Err(e),
+    Err(e) => {
+      eprintln!("failed to initialize logging, continuing without it: {}", e);
+      Ok(None)
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  #[test]
+  fn test_parse_size() {
+    assert_eq!(parse_size("512").unwrap(), 512);
+    assert_eq!(parse_size("10MB").unwrap(), 10 << 20);
+    assert_eq!(parse_size("1 gib").unwrap(), 1 << 30);
+    assert!(parse_size("10XB").is_err());
+    assert!(parse_size("MB").is_err());
+    assert!(parse_size("99999999999g").is_err());
+  }
+  #[test]
+  fn test_parse_spec() {
+    assert!(parse_spec("debug").is_ok());
+    assert!(parse_spec("info,shed=debug,hyper=warn").is_ok());
+    assert!(parse_spec("shed=loud").is_err());
+    assert!(parse_spec("loud").is_err());
+  }
+  #[test]
+  fn test_live_file() {
+    let mut cfg = LogConfig {
+      file: Some("/var/log/shs.log".into()),
+      ..LogConfig::default()
+    };
+    assert_eq!(cfg.live_file(), Some("/var/log/shs.log".into()));
+    cfg.rotate = Some("daily".to_string());
+    assert_eq!(cfg.live_file(), Some("/var/log/shs_rCURRENT.log".into()));
+    assert!(cfg.validate().is_empty());
+    cfg.file = None;
+    assert_eq!(cfg.validate()[0].0, "log.rotate");
+  }
+}
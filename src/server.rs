@@ -0,0 +1,1004 @@
+//! server.rs --- shed server
+/*!
+The server logic behind the `shs` program. It is kept in the library so
+that it can be launched from `shs` directly or from `shc daemon`, or
+embedded elsewhere (like the integration tests).
+
+On Unix a running server reloads its configuration on SIGHUP, see
+`Reload` for which settings take effect.
+*/
+use crate::{config::expand_opt_path, logger, Config, LogConfig};
+use flexi_logger::LoggerHandle;
+use futures::future::select_all;
+use hyper::{
+  header::{HeaderValue, CONTENT_TYPE},
+  server::conn::Http,
+  service::service_fn,
+  Body, Method, Request, Response, StatusCode,
+};
+use rlib::{
+  logger::log::{self, debug, error, info, warn},
+  util::{cli::ArgMatches, Result as UResult},
+};
+use serde::{Deserialize, Deserializer, Serialize};
+use std::{
+  collections::HashMap,
+  convert::Infallible,
+  future::Future,
+  io,
+  net::{AddrParseError, IpAddr, SocketAddr},
+  path::{Path, PathBuf},
+  sync::{
+    atomic::{AtomicBool, AtomicU64, Ordering},
+    Arc, Mutex,
+  },
+  time::{Duration, Instant},
+};
+use thiserror::Error;
+#[cfg(unix)]
+use tokio::net::UnixListener;
+use tokio::{
+  io::{AsyncRead, AsyncWrite},
+  net::TcpListener,
+  sync::{mpsc, watch, OwnedSemaphorePermit, Semaphore},
+};
+
+/// Errors returned by the server
+#[derive(Error, Debug)]
+pub enum ServerError {
+  /// the listener couldn't be bound
+  #[error("failed to bind {addr}: {source}")]
+  Bind { addr: String, source: io::Error },
+  /// invalid server configuration
+  #[error("invalid server config: {0}")]
+  Config(String),
+  /// the PID file belongs to a running process
+  #[error("{path} belongs to running process {pid}")]
+  PidFileInUse { path: PathBuf, pid: u32 },
+  /// signal handlers couldn't be registered
+  #[error("failed to register signal handler: {0}")]
+  Signal(io::Error),
+  #[error("invalid address: {0}")]
+  AddrParse(#[from] AddrParseError),
+  #[error(transparent)]
+  Io(#[from] io::Error),
+}
+
+/// Server result type
+pub type Result<T> = std::result::Result<T, ServerError>;
+
+/// Server configuration type
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct ServerConfig {
+  /// IP addresses to listen on, a single address is also accepted
+  #[serde(deserialize_with = "one_or_many")]
+  pub bind: Vec<String>,
+  /// TCP port to listen on, 0 picks an ephemeral port
+  pub port: u16,
+  /// HTTP path of the health check endpoint
+  pub health_path: String,
+  /// listen on this Unix socket instead of `bind` and `port`
+  #[serde(deserialize_with = "expand_opt_path")]
+  pub socket: Option<PathBuf>,
+  /// serve Prometheus metrics at `metrics_path`
+  pub metrics: bool,
+  /// HTTP path of the metrics endpoint
+  pub metrics_path: String,
+  /// maximum number of concurrent connections, 0 is unlimited. Further
+  /// connections wait to be accepted until one closes.
+  pub max_connections: usize,
+  /// seconds to keep accepting connections once shutdown begins,
+  /// answering the health check with 503 so load balancers stop
+  /// routing new traffic here
+  pub drain_period: u64,
+  /// seconds to wait for open connections to finish on shutdown
+  /// before exiting anyway
+  pub shutdown_timeout: u64,
+  /// seconds a request may take to be handled, or a client to send
+  /// the request headers, before its connection is closed, 0 is
+  /// unlimited
+  pub request_timeout: u64,
+  /// use this inherited listening socket instead of binding one, for
+  /// systemd socket activation (Unix only). Takes precedence over
+  /// `socket`, `bind` and `port`.
+  pub bind_fd: Option<i32>,
+  /// write the server PID to this file while running
+  #[serde(deserialize_with = "expand_opt_path")]
+  pub pid_file: Option<PathBuf>,
+  /// directory for server state, set from `Config::data_dir`
+  #[serde(skip)]
+  pub data_dir: Option<PathBuf>,
+}
+
+impl Default for ServerConfig {
+  fn default() -> Self {
+    ServerConfig {
+      bind: vec!["127.0.0.1".to_string()],
+      port: 8080,
+      health_path: "/health".to_string(),
+      socket: None,
+      metrics: false,
+      metrics_path: "/metrics".to_string(),
+      max_connections: 1024,
+      drain_period: 5,
+      shutdown_timeout: 30,
+      request_timeout: 60,
+      bind_fd: None,
+      pid_file: None,
+      data_dir: None,
+    }
+  }
+}
+
+/// Deserialize a string or a list of strings
+fn one_or_many<'de, D: Deserializer<'de>>(d: D) -> std::result::Result<Vec<String>, D::Error> {
+  #[derive(Deserialize)]
+  #[serde(untagged)]
+  enum OneOrMany {
+    One(String),
+    Many(Vec<String>),
+  }
+  Ok(match OneOrMany::deserialize(d)? {
+    OneOrMany::One(s) => vec![s],
+    OneOrMany::Many(v) => v,
+  })
+}
+
+impl ServerConfig {
+  /// Override values with any server args present in `cli`
+  pub fn apply(&mut self, cli: &ArgMatches) -> Result<()> {
+    if let Some(b) = cli.values_of("bind") {
+      self.bind = b.map(String::from).collect();
+    }
+    if let Some(p) = cli.value_of("port") {
+      self.port = p
+        .parse()
+        .map_err(|_| ServerError::Config(format!("invalid port: {}", p)))?;
+    }
+    if let Some(h) = cli.value_of("health_path") {
+      self.health_path = h.to_string();
+    }
+    if let Some(s) = cli.value_of("socket") {
+      self.socket = Some(s.into());
+    }
+    if cli.is_present("metrics") {
+      self.metrics = true;
+    }
+    if let Some(m) = cli.value_of("metrics_path") {
+      self.metrics_path = m.to_string();
+    }
+    if let Some(m) = cli.value_of("max_connections") {
+      self.max_connections = m
+        .parse()
+        .map_err(|_| ServerError::Config(format!("invalid max connections: {}", m)))?;
+    }
+    if let Some(d) = cli.value_of("drain_period") {
+      self.drain_period = d
+        .parse()
+        .map_err(|_| ServerError::Config(format!("invalid drain period: {}", d)))?;
+    }
+    if let Some(t) = cli.value_of("shutdown_timeout") {
+      self.shutdown_timeout = t
+        .parse()
+        .map_err(|_| ServerError::Config(format!("invalid shutdown timeout: {}", t)))?;
+    }
+    if let Some(t) = cli.value_of("request_timeout") {
+      self.request_timeout = t
+        .parse()
+        .map_err(|_| ServerError::Config(format!("invalid request timeout: {}", t)))?;
+    }
+    if let Some(fd) = cli.value_of("bind_fd") {
+      self.bind_fd = Some(
+        fd.parse()
+          .map_err(|_| ServerError::Config(format!("invalid bind fd: {}", fd)))?,
+      );
+    }
+    if let Some(p) = cli.value_of("pid_file") {
+      self.pid_file = Some(p.into());
+    }
+    Ok(())
+  }
+
+  /// Check the values of this config, returning any issues as
+  /// '(field, reason)' pairs.
+  pub fn validate(&self) -> Vec<(String, String)> {
+    let mut issues = vec![];
+    if let Err(e) = self.addrs() {
+      issues.push(("server.bind".to_string(), e.to_string()));
+    }
+    if !self.health_path.starts_with('/') {
+      issues.push((
+        "server.health_path".to_string(),
+        "must start with '/'".to_string(),
+      ));
+    }
+    if !self.metrics_path.starts_with('/') {
+      issues.push((
+        "server.metrics_path".to_string(),
+        "must start with '/'".to_string(),
+      ));
+    } else if self.metrics && self.metrics_path == self.health_path {
+      issues.push((
+        "server.metrics_path".to_string(),
+        "must differ from server.health_path".to_string(),
+      ));
+    }
+    issues
+  }
+
+  /// Return the socket addresses to listen on
+  pub fn addrs(&self) -> Result<Vec<SocketAddr>> {
+    if self.bind.is_empty() {
+      return Err(ServerError::Config("no bind address".to_string()));
+    }
+    self
+      .bind
+      .iter()
+      .map(|b| Ok(SocketAddr::new(b.parse::<IpAddr>()?, self.port)))
+      .collect()
+  }
+}
+
+/// A connection accepted by a `Listener`
+trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Stream for T {}
+
+/// A bound server socket
+enum Listener {
+  /// one listener per bind address
+  Tcp(Vec<TcpListener>),
+  /// the socket file is removed when the listener is dropped
+  #[cfg(unix)]
+  Unix(UnixListener, PathBuf),
+  /// a Unix socket inherited as a file descriptor, which is left for
+  /// its owner to clean up
+  #[cfg(unix)]
+  UnixFd(UnixListener, i32),
+}
+
+impl Listener {
+  /// Bind the socket described by `cfg`
+  async fn bind(cfg: &ServerConfig) -> Result<Self> {
+    if let Some(fd) = cfg.bind_fd {
+      return Listener::from_fd(fd);
+    }
+    if let Some(path) = &cfg.socket {
+      return Listener::bind_unix(path.clone());
+    }
+    let mut ls = vec![];
+    for addr in cfg.addrs()? {
+      match TcpListener::bind(addr).await {
+        Ok(l) => {
+          info!("bound {}", l.local_addr()?);
+          ls.push(l);
+        }
+        Err(source) => {
+          // dropping the listeners bound so far releases them
+          if !ls.is_empty() {
+            warn!(
+              "failed to bind {}, releasing {} bound address(es)",
+              addr,
+              ls.len()
+            );
+          }
+          return Err(ServerError::Bind {
+            addr: addr.to_string(),
+            source,
+          });
+        }
+      }
+    }
+    Ok(Listener::Tcp(ls))
+  }
+
+  /// Bind a Unix socket at `path`, replacing a stale socket file left
+  /// behind by a previous server. Anything other than a socket at
+  /// `path` is left alone and fails to bind.
+  #[cfg(unix)]
+  fn bind_unix(path: PathBuf) -> Result<Self> {
+    use std::os::unix::fs::FileTypeExt;
+    let err = |source| ServerError::Bind {
+      addr: path.display().to_string(),
+      source,
+    };
+    match std::fs::symlink_metadata(&path) {
+      Ok(m) if !m.file_type().is_socket() => {
+        return Err(err(io::Error::new(
+          io::ErrorKind::AlreadyExists,
+          "path exists and is not a socket",
+        )));
+      }
+      Ok(_) => {
+        if std::os::unix::net::UnixStream::connect(&path).is_ok() {
+          return Err(err(io::Error::new(
+            io::ErrorKind::AddrInUse,
+            "socket is in use",
+          )));
+        }
+        info!("removing stale socket {}", path.display());
+        std::fs::remove_file(&path).map_err(err)?;
+      }
+      Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+      Err(e) => return Err(err(e)),
+    }
+    let l = UnixListener::bind(&path).map_err(err)?;
+    Ok(Listener::Unix(l, path))
+  }
+
+  /// Use the inherited listening socket `fd`, as passed by systemd
+  /// socket activation. Fails unless `fd` is a listening TCP or Unix
+  /// socket.
+  #[cfg(unix)]
+  fn from_fd(fd: i32) -> Result<Self> {
+    use std::{mem, os::unix::io::FromRawFd};
+    let err = |msg: String| ServerError::Config(format!("bind fd {}: {}", fd, msg));
+    let mut accepting: libc::c_int = 0;
+    let mut len = mem::size_of::<libc::c_int>() as libc::socklen_t;
+    let res = unsafe {
+      libc::getsockopt(
+        fd,
+        libc::SOL_SOCKET,
+        libc::SO_ACCEPTCONN,
+        &mut accepting as *mut libc::c_int as *mut libc::c_void,
+        &mut len,
+      )
+    };
+    if res != 0 {
+      return Err(err(format!(
+        "not a socket ({})",
+        io::Error::last_os_error()
+      )));
+    }
+    if accepting == 0 {
+      return Err(err("socket is not listening".to_string()));
+    }
+    let mut addr: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let mut len = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    let res = unsafe {
+      libc::getsockname(
+        fd,
+        &mut addr as *mut libc::sockaddr_storage as *mut libc::sockaddr,
+        &mut len,
+      )
+    };
+    if res != 0 {
+      return Err(err(io::Error::last_os_error().to_string()));
+    }
+    // the fd is known to be a listening socket, so it can be owned
+    match addr.ss_family as libc::c_int {
+      libc::AF_INET | libc::AF_INET6 => {
+        let l = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+        l.set_nonblocking(true)?;
+        let l = TcpListener::from_std(l)?;
+        info!("using fd {} bound to {}", fd, l.local_addr()?);
+        Ok(Listener::Tcp(vec![l]))
+      }
+      libc::AF_UNIX => {
+        let l = unsafe { std::os::unix::net::UnixListener::from_raw_fd(fd) };
+        l.set_nonblocking(true)?;
+        info!("using unix socket fd {}", fd);
+        Ok(Listener::UnixFd(UnixListener::from_std(l)?, fd))
+      }
+      f => Err(err(format!("unsupported address family {}", f))),
+    }
+  }
+
+  #[cfg(not(unix))]
+  fn from_fd(_fd: i32) -> Result<Self> {
+    Err(ServerError::Config(
+      "binding a file descriptor is not supported on this platform".to_string(),
+    ))
+  }
+
+  #[cfg(not(unix))]
+  fn bind_unix(_path: PathBuf) -> Result<Self> {
+    Err(ServerError::Config(
+      "unix sockets are not supported on this platform".to_string(),
+    ))
+  }
+
+  /// Return a description of the addresses being listened on
+  fn local_addr(&self) -> Result<String> {
+    match self {
+      Listener::Tcp(ls) => {
+        let addrs = ls
+          .iter()
+          .map(|l| Ok(l.local_addr()?.to_string()))
+          .collect::<Result<Vec<_>>>()?;
+        Ok(addrs.join(", "))
+      }
+      #[cfg(unix)]
+      Listener::Unix(_, p) => Ok(p.display().to_string()),
+      #[cfg(unix)]
+      Listener::UnixFd(_, fd) => Ok(format!("fd {}", fd)),
+    }
+  }
+
+  /// Accept a connection, returning it with a description of the peer
+  async fn accept(&self) -> io::Result<(Box<dyn Stream>, String)> {
+    match self {
+      Listener::Tcp(ls) => {
+        let (res, _, _) = select_all(ls.iter().map(|l| Box::pin(l.accept()))).await;
+        let (s, peer) = res?;
+        Ok((Box::new(s), peer.to_string()))
+      }
+      #[cfg(unix)]
+      Listener::Unix(l, p) => {
+        let (s, _) = l.accept().await?;
+        Ok((Box::new(s), p.display().to_string()))
+      }
+      #[cfg(unix)]
+      Listener::UnixFd(l, fd) => {
+        let (s, _) = l.accept().await?;
+        Ok((Box::new(s), format!("fd {}", fd)))
+      }
+    }
+  }
+}
+
+#[cfg(unix)]
+impl Drop for Listener {
+  fn drop(&mut self) {
+    if let Listener::Unix(_, p) = self {
+      if let Err(e) = std::fs::remove_file(&p) {
+        warn!("failed to remove socket {}: {}", p.display(), e);
+      }
+    }
+  }
+}
+
+/// Return true if a process with `pid` is running
+#[cfg(unix)]
+fn alive(pid: u32) -> bool {
+  // signal 0 only checks that the process exists and may be signaled,
+  // EPERM means it exists but belongs to someone else
+  unsafe { libc::kill(pid as libc::pid_t, 0) == 0 }
+  || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+#[cfg(not(unix))]
+fn alive(_pid: u32) -> bool {
+  // there is no portable check, so assume the file is stale
+  false
+}
+
+/// Return the PID in the file at `path` if that process is still
+/// running. A missing, unreadable, invalid, or stale file returns
+/// None.
+pub fn pid_file_owner(path: &Path) -> Option<u32> {
+  let pid: u32 = std::fs::read_to_string(path).ok()?.trim().parse().ok()?;
+  // 0 and values past i32::MAX aren't PIDs, kill() would signal the
+  // process group or every process instead
+  if pid == 0 || i32::try_from(pid).is_err() {
+    return None;
+  }
+  (pid != std::process::id() && alive(pid)).then(|| pid)
+}
+
+/// A PID file written on startup, removed when dropped
+struct PidFile(PathBuf);
+
+impl PidFile {
+  /// Write the current PID to `path`, unless it belongs to another
+  /// running process
+  fn create(path: &Path) -> Result<Self> {
+    if let Some(pid) = pid_file_owner(path) {
+      return Err(ServerError::PidFileInUse {
+        path: path.into(),
+        pid,
+      });
+    }
+    if path.exists() {
+      info!("replacing stale pid file {}", path.display());
+    }
+    std::fs::write(path, format!("{}\n", std::process::id()))?;
+    info!("wrote pid file {}", path.display());
+    Ok(PidFile(path.into()))
+  }
+}
+
+impl Drop for PidFile {
+  fn drop(&mut self) {
+    if let Err(e) = std::fs::remove_file(&self.0) {
+      warn!("failed to remove pid file {}: {}", self.0.display(), e);
+    }
+  }
+}
+
+/// Create the data directory `dir` if it is missing, and check that
+/// it is writable.
+fn prepare_data_dir(dir: &Path) -> Result<()> {
+  std::fs::create_dir_all(dir).map_err(|e| {
+    ServerError::Config(format!(
+      "failed to create data directory {}: {}",
+      dir.display(),
+      e
+    ))
+  })?;
+  let probe = dir.join(".shs");
+  std::fs::File::create(&probe)
+    .and_then(|_| std::fs::remove_file(&probe))
+    .map_err(|e| {
+      ServerError::Config(format!(
+        "data directory {} is not writable: {}",
+        dir.display(),
+        e
+      ))
+    })
+}
+
+/// Reloads the configuration of a running server. Only `log.level`
+/// is applied at runtime, changes to any other setting are logged as
+/// ignored until the server is restarted.
+pub struct Reload {
+  /// resolve the new configuration, usually `Config::resolve` with
+  /// the original CLI args so that flags still take precedence
+  resolve: Box<dyn Fn() -> UResult<Config> + Send>,
+  /// the logging config currently in effect
+  log: LogConfig,
+  /// handle to the running logger, if it was started by
+  /// `logger::init`
+  handle: Option<LoggerHandle>,
+}
+
+/// Return the names of the fields which differ between `a` and `b`
+fn changed<T: Serialize>(a: &T, b: &T) -> UResult<Vec<String>> {
+  let (a, b) = (toml::Value::try_from(a)?, toml::Value::try_from(b)?);
+  let (a, b) = match (a.as_table(), b.as_table()) {
+    (Some(a), Some(b)) => (a, b),
+    _ => return Ok(vec![]),
+  };
+  let mut keys: Vec<String> = a.keys().chain(b.keys()).cloned().collect();
+  keys.sort();
+  keys.dedup();
+  Ok(keys.into_iter().filter(|k| a.get(k) != b.get(k)).collect())
+}
+
+impl Reload {
+  /// Create a new `Reload` for a server started with the logging
+  /// config `log`, and logger `handle`.
+  pub fn new<F>(log: LogConfig, handle: Option<LoggerHandle>, resolve: F) -> Self
+  where
+    F: Fn() -> UResult<Config> + Send + 'static,
+  {
+    Reload {
+      resolve: Box::new(resolve),
+      log,
+      handle,
+    }
+  }
+
+  /// Resolve the configuration again and apply it to a server running
+  /// with `server`. Returns the dotted names of changed settings which
+  /// were ignored. An invalid config is rejected without applying
+  /// anything.
+  pub fn reload(&mut self, server: &ServerConfig) -> UResult<Vec<String>> {
+    let cfg = (self.resolve)()?;
+    cfg.ensure_valid()?;
+    let mut ignored = vec![];
+    if server.data_dir.as_ref() != Some(&cfg.data_dir) {
+      ignored.push("data_dir".to_string());
+    }
+    for k in changed(&self.log, &cfg.log)? {
+      if k != "level" {
+        ignored.push(format!("log.{}", k));
+      }
+    }
+    // data_dir isn't serialized, it is compared above
+    for k in changed(server, &cfg.server)? {
+      ignored.push(format!("server.{}", k));
+    }
+    if cfg.log.level != self.log.level {
+      match &self.handle {
+        Some(h) => {
+          h.set_new_spec(logger::parse_spec(&cfg.log.level)?);
+          info!(
+            "log level changed from {} to {}",
+            self.log.level, cfg.log.level
+          );
+          self.log.level = cfg.log.level;
+        }
+        None => ignored.push("log.level".to_string()),
+      }
+    }
+    for k in ignored.iter() {
+      warn!("ignoring change to {}, restart shs to apply it", k);
+    }
+    Ok(ignored)
+  }
+}
+
+/// State shared by the connections of a running server
+struct State {
+  cfg: ServerConfig,
+  started: Instant,
+  /// number of requests received
+  requests: AtomicU64,
+  /// set once shutdown begins
+  draining: AtomicBool,
+  /// permits for `cfg.max_connections`, None if unlimited
+  limit: Option<Arc<Semaphore>>,
+  /// peers of the open connections, by connection id
+  conns: Mutex<HashMap<u64, String>>,
+  next_conn: AtomicU64,
+}
+
+impl State {
+  /// Record a new connection from `peer`, returning its id
+  fn open(&self, peer: String) -> u64 {
+    let id = self.next_conn.fetch_add(1, Ordering::Relaxed);
+    self.conns.lock().unwrap().insert(id, peer);
+    id
+  }
+
+  /// Forget the connection `id`
+  fn close(&self, id: u64) {
+    self.conns.lock().unwrap().remove(&id);
+  }
+
+  /// Wait for a connection permit. Returns None when connections are
+  /// unlimited.
+  async fn permit(&self) -> Option<OwnedSemaphorePermit> {
+    let sem = self.limit.as_ref()?;
+    match sem.clone().try_acquire_owned() {
+      Ok(p) => Some(p),
+      Err(_) => {
+        warn!(
+          "connection limit of {} reached, waiting for a connection to close",
+          self.cfg.max_connections
+        );
+        sem.clone().acquire_owned().await.ok()
+      }
+    }
+  }
+
+  /// Render the server metrics in the Prometheus text format
+  fn metrics(&self) -> String {
+    let mut out = String::new();
+    for (name, kind, help, labels, value) in [
+      (
+        "shs_uptime_seconds",
+        "gauge",
+        "Seconds since the server started.",
+        String::new(),
+        self.started.elapsed().as_secs_f64().to_string(),
+      ),
+      (
+        "shs_requests_total",
+        "counter",
+        "HTTP requests received.",
+        String::new(),
+        self.requests.load(Ordering::Relaxed).to_string(),
+      ),
+      (
+        "shs_build_info",
+        "gauge",
+        "Build information, always 1.",
+        format!("{{version=\"{}\"}}", crate::version()),
+        "1".to_string(),
+      ),
+    ] {
+      out.push_str(&format!(
+        "# HELP {} {}\n# TYPE {} {}\n{}{} {}\n",
+        name, help, name, kind, name, labels, value
+      ));
+    }
+    out
+  }
+}
+
+/// Handle a single HTTP request. GET requests to the health check
+/// path respond with the server version, or 503 once shutdown has
+/// begun, and to the metrics path (when enabled) with metrics.
+/// Everything else is 404.
+async fn handle(
+  state: Arc<State>,
+  req: Request<Body>,
+) -> std::result::Result<Response<Body>, Infallible> {
+  debug!("{} {}", req.method(), req.uri());
+  state.requests.fetch_add(1, Ordering::Relaxed);
+  let cfg = &state.cfg;
+  let mut res = Response::new(Body::empty());
+  match (req.method(), req.uri().path()) {
+    // tell load balancers to stop routing here during shutdown
+    (&Method::GET, p) if p == cfg.health_path && state.draining.load(Ordering::Relaxed) => {
+      *res.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+      *res.body_mut() = Body::from("draining\n");
+    }
+    (&Method::GET, p) if p == cfg.health_path => {
+      *res.body_mut() = Body::from(format!("{}\n", crate::version()));
+    }
+    (&Method::GET, p) if cfg.metrics && p == cfg.metrics_path => {
+      res.headers_mut().insert(
+        CONTENT_TYPE,
+        HeaderValue::from_static("text/plain; version=0.0.4"),
+      );
+      *res.body_mut() = Body::from(state.metrics());
+    }
+    _ => *res.status_mut() = StatusCode::NOT_FOUND,
+  }
+  Ok(res)
+}
+
+/// Handle a request like `handle`, answering with 503 if it takes
+/// longer than the request timeout.
+async fn handle_timeout(
+  state: Arc<State>,
+  req: Request<Body>,
+) -> std::result::Result<Response<Body>, Infallible> {
+  let t = state.cfg.request_timeout;
+  if t == 0 {
+    return handle(state, req).await;
+  }
+  let (method, uri) = (req.method().clone(), req.uri().clone());
+  match tokio::time::timeout(Duration::from_secs(t), handle(state, req)).await {
+    Ok(res) => res,
+    Err(_) => {
+      warn!("{} {} exceeded the {}s request timeout", method, uri, t);
+      let mut res = Response::new(Body::from("request timed out\n"));
+      *res.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+      Ok(res)
+    }
+  }
+}
+
+/// Wait for a shutdown signal. On Unix this is SIGINT or SIGTERM,
+/// everywhere else only Ctrl-C is registered.
+#[cfg(unix)]
+async fn shutdown_signal() -> Result<()> {
+  use tokio::signal::unix::{signal, SignalKind};
+  let mut int = signal(SignalKind::interrupt()).map_err(ServerError::Signal)?;
+  let mut term = signal(SignalKind::terminate()).map_err(ServerError::Signal)?;
+  tokio::select! {
+    _ = int.recv() => info!("received SIGINT"),
+    _ = term.recv() => info!("received SIGTERM"),
+  }
+  Ok(())
+}
+
+#[cfg(not(unix))]
+async fn shutdown_signal() -> Result<()> {
+  tokio::signal::ctrl_c().await.map_err(ServerError::Signal)?;
+  info!("received Ctrl-C");
+  Ok(())
+}
+
+/// Stream of SIGHUP signals
+#[cfg(unix)]
+type Hangup = tokio::signal::unix::Signal;
+#[cfg(not(unix))]
+type Hangup = ();
+
+#[cfg(unix)]
+fn hangup_signal() -> Result<Hangup> {
+  use tokio::signal::unix::{signal, SignalKind};
+  signal(SignalKind::hangup()).map_err(ServerError::Signal)
+}
+
+#[cfg(not(unix))]
+fn hangup_signal() -> Result<Hangup> {
+  Ok(())
+}
+
+/// Wait for the next SIGHUP, forever where there are none
+async fn hangup(sig: &mut Hangup) {
+  #[cfg(unix)]
+  if sig.recv().await.is_some() {
+    return;
+  }
+  let _ = sig;
+  std::future::pending::<()>().await
+}
+
+/// Run the server until a shutdown signal is received, reloading the
+/// configuration with `reload` on SIGHUP. The logger must already be
+/// initialized, it is flushed before returning.
+pub async fn run(cfg: ServerConfig, reload: Option<Reload>) -> Result<()> {
+  Server::bind(cfg).await?.run(reload).await
+}
+
+/// Run the server until the `shutdown` future resolves, see
+/// `Server::run_until`.
+pub async fn run_until<F>(cfg: ServerConfig, shutdown: F) -> Result<()>
+where
+  F: Future<Output = Result<()>>,
+{
+  Server::bind(cfg).await?.run_until(shutdown).await
+}
+
+/// A server which is listening but not yet serving connections. This
+/// allows the bound address to be read before running, e.g. when
+/// binding port 0.
+pub struct Server {
+  cfg: ServerConfig,
+  listener: Listener,
+  /// removes the PID file when the server is dropped
+  pid: Option<PidFile>,
+}
+
+impl Server {
+  /// Prepare the data directory, write the PID file, and bind the
+  /// listener described by `cfg`.
+  pub async fn bind(cfg: ServerConfig) -> Result<Self> {
+    info!("starting shs {}: {:?}", crate::version(), cfg);
+    if let Some(dir) = &cfg.data_dir {
+      prepare_data_dir(dir)?;
+    }
+    let pid = cfg.pid_file.as_deref().map(PidFile::create).transpose()?;
+    let listener = Listener::bind(&cfg).await?;
+    info!("listening on {}", listener.local_addr()?);
+    Ok(Server { cfg, listener, pid })
+  }
+
+  /// Return the TCP addresses being listened on, which is empty for
+  /// Unix sockets.
+  pub fn local_addrs(&self) -> Result<Vec<SocketAddr>> {
+    match &self.listener {
+      Listener::Tcp(ls) => Ok(
+        ls.iter()
+          .map(TcpListener::local_addr)
+          .collect::<io::Result<_>>()?,
+      ),
+      #[cfg(unix)]
+      _ => Ok(vec![]),
+    }
+  }
+
+  /// Serve until a shutdown signal is received, like `run`
+  pub async fn run(self, reload: Option<Reload>) -> Result<()> {
+    self.serve(shutdown_signal(), reload).await
+  }
+
+  /// Serve until the `shutdown` future resolves. New connections are
+  /// accepted for another `cfg.drain_period` seconds, and open
+  /// connections are then given `cfg.shutdown_timeout` seconds to
+  /// finish before they are dropped.
+  pub async fn run_until<F>(self, shutdown: F) -> Result<()>
+  where
+    F: Future<Output = Result<()>>,
+  {
+    self.serve(shutdown, None).await
+  }
+
+  async fn serve<F>(self, shutdown: F, reload: Option<Reload>) -> Result<()>
+  where
+    F: Future<Output = Result<()>>,
+  {
+    let Server {
+      cfg,
+      listener,
+      pid: _pid,
+    } = self;
+    serve(cfg, listener, shutdown, reload).await
+  }
+}
+
+async fn serve<F>(
+  cfg: ServerConfig,
+  listener: Listener,
+  shutdown: F,
+  mut reload: Option<Reload>,
+) -> Result<()>
+where
+  F: Future<Output = Result<()>>,
+{
+  let mut hup = hangup_signal()?;
+  let state = Arc::new(State {
+    limit: (cfg.max_connections > 0).then(|| {
+      Arc::new(Semaphore::new(
+        cfg.max_connections.min(Semaphore::MAX_PERMITS),
+      ))
+    }),
+    cfg,
+    started: Instant::now(),
+    requests: AtomicU64::new(0),
+    draining: AtomicBool::new(false),
+    conns: Mutex::new(HashMap::new()),
+    next_conn: AtomicU64::new(0),
+  });
+  // each connection holds a `done` sender and watches `stop`, so
+  // shutdown can tell them to finish and wait until they have
+  let (stop_tx, stop_rx) = watch::channel(false);
+  let (done_tx, mut done_rx) = mpsc::channel::<()>(1);
+  // once shutdown begins the listener stays open for the drain period,
+  // so health checks get a 503 instead of a refused connection
+  let drain = tokio::time::sleep(Duration::ZERO);
+  tokio::pin!(shutdown, drain);
+  let mut draining = false;
+  loop {
+    tokio::select! {
+      res = &mut shutdown, if !draining => {
+        res?;
+        let period = state.cfg.drain_period;
+        info!("draining, answering health checks with 503 for {}s", period);
+        state.draining.store(true, Ordering::Relaxed);
+        drain
+          .as_mut()
+          .reset(tokio::time::Instant::now() + Duration::from_secs(period));
+        draining = true;
+      }
+      _ = &mut drain, if draining => break,
+      _ = hangup(&mut hup) => match reload.as_mut() {
+        Some(r) => {
+          info!("received SIGHUP, reloading config");
+          if let Err(e) = r.reload(&state.cfg) {
+            error!("failed to reload config: {}", e);
+          }
+        }
+        None => info!("received SIGHUP, reloading is not enabled"),
+      },
+      (conn, permit) = async {
+        let permit = state.permit().await;
+        (listener.accept().await, permit)
+      } => match conn {
+        Ok((stream, peer)) => {
+          debug!("accepted connection from {}", peer);
+          let id = state.open(peer.clone());
+          let (state, svc_state) = (state.clone(), state.clone());
+          let svc = service_fn(move |req| handle_timeout(svc_state.clone(), req));
+          let (mut stop, done) = (stop_rx.clone(), done_tx.clone());
+          tokio::spawn(async move {
+            let mut http = Http::new();
+            if state.cfg.request_timeout > 0 {
+              // a client which stalls sending a request is closed with
+              // the same timeout, while one which keeps sending
+              // requests on the connection is never cut off
+              http.http1_header_read_timeout(Duration::from_secs(state.cfg.request_timeout));
+            }
+            let conn = http.serve_connection(stream, svc);
+            tokio::pin!(conn);
+            let res = tokio::select! {
+              res = conn.as_mut() => res,
+              _ = stop.changed() => {
+                conn.as_mut().graceful_shutdown();
+                conn.as_mut().await
+              }
+            };
+            if let Err(e) = res {
+              warn!("closing connection from {}: {}", peer, e);
+            }
+            state.close(id);
+            drop((permit, done));
+          });
+        }
+        Err(e) => warn!("failed to accept connection: {}", e),
+      },
+    }
+  }
+  drop(listener);
+  let timeout = state.cfg.shutdown_timeout;
+  info!(
+    "shutting down, waiting up to {}s for {} connection(s)",
+    timeout,
+    state.conns.lock().unwrap().len()
+  );
+  let _ = stop_tx.send(true);
+  drop(done_tx);
+  let deadline = tokio::time::Instant::now() + Duration::from_secs(timeout);
+  let mut tick = tokio::time::interval(Duration::from_secs(1));
+  tick.tick().await;
+  let finished = loop {
+    tokio::select! {
+      // recv returns once every connection has dropped its sender
+      _ = done_rx.recv() => break true,
+      _ = tokio::time::sleep_until(deadline) => break false,
+      _ = tick.tick() => info!(
+        "draining, {} active connection(s) remaining",
+        state.conns.lock().unwrap().len()
+      ),
+    }
+  };
+  if !finished {
+    let conns = state.conns.lock().unwrap();
+    let peers: Vec<&str> = conns.values().map(String::as_str).collect();
+    warn!(
+      "shutdown timed out after {}s, closing {} unfinished connection(s): {}",
+      timeout,
+      peers.len(),
+      peers.join(", ")
+    );
+  }
+  log::logger().flush();
+  Ok(())
+}
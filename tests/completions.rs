@@ -0,0 +1,45 @@
+//! tests/completions.rs --- shell completion generation tests
+use shed::{build_cli, build_server_cli, generate_completions};
+use std::{env, fs, process};
+
+#[test]
+fn completions_shc() {
+  let dir = env::temp_dir().join(format!("shed-completions-{}", process::id()));
+  fs::create_dir_all(&dir).unwrap();
+  let files = generate_completions(&mut build_cli(), "shc", &dir).unwrap();
+  assert_eq!(files.len(), 4);
+  for f in files {
+    let s = fs::read_to_string(&f).unwrap();
+    assert!(!s.is_empty(), "{} is empty", f.display());
+    for cmd in ["init", "daemon", "completions", "config"] {
+      assert!(s.contains(cmd), "{} doesn't mention {}", f.display(), cmd);
+    }
+  }
+  fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn completions_shs() {
+  let dir = env::temp_dir().join(format!("shs-completions-{}", process::id()));
+  fs::create_dir_all(&dir).unwrap();
+  for f in generate_completions(&mut build_server_cli(), "shs", &dir).unwrap() {
+    let s = fs::read_to_string(&f).unwrap();
+    assert!(
+      s.contains("--port"),
+      "{} doesn't mention --port",
+      f.display()
+    );
+  }
+  fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn completions_list() {
+  assert!(build_cli()
+    .try_get_matches_from(vec!["shc", "completions", "--list"])
+    .is_ok());
+  // a shell is required without --list
+  assert!(build_cli()
+    .try_get_matches_from(vec!["shc", "completions"])
+    .is_err());
+}
@@ -0,0 +1,131 @@
+//! tests/config.rs --- config resolution tests
+use shed::{build_server_cli, Config};
+use std::{env, fs, path::PathBuf, process, sync::Mutex};
+
+/// Held by tests which set or depend on SHED_LOG
+static ENV: Mutex<()> = Mutex::new(());
+
+/// Write `contents` to a temporary config file named `name`
+fn tmp_config(name: &str, contents: &str) -> PathBuf {
+  let dir = env::temp_dir().join(format!("shed-test-{}", process::id()));
+  fs::create_dir_all(&dir).unwrap();
+  let p = dir.join(name);
+  fs::write(&p, contents).unwrap();
+  p
+}
+
+#[test]
+fn config_missing() {
+  let cli = build_server_cli().get_matches_from(vec!["shs", "--config", "/nonexistent.toml"]);
+  let e = Config::resolve(&cli).unwrap_err();
+  assert!(e.to_string().contains("not found"));
+}
+
+#[test]
+fn config_precedence() {
+  let _env = ENV.lock().unwrap();
+  let p = tmp_config(
+    "precedence.toml",
+    "[log]\nlevel = \"debug\"\n[server]\nport = 9000\nbind = \"0.0.0.0\"\n",
+  );
+  let path = p.to_str().unwrap();
+  let cli = build_server_cli().get_matches_from(vec!["shs", "--config", path, "--port", "9001"]);
+  let cfg = Config::resolve(&cli).unwrap();
+  assert_eq!(cfg.log.level, "debug");
+  assert_eq!(cfg.server.bind, vec!["0.0.0.0"]);
+  assert_eq!(cfg.server.port, 9001);
+}
+
+#[test]
+fn config_verbose() {
+  let cli = build_server_cli().get_matches_from(vec!["shs", "--log-level", "warn", "-v"]);
+  let e = Config::resolve(&cli).unwrap_err();
+  assert!(e.to_string().contains("--log-level"));
+}
+
+#[test]
+fn config_verbose_env() {
+  // unlike --log-level, SHED_LOG is overridden by -v
+  let _env = ENV.lock().unwrap();
+  env::set_var("SHED_LOG", "warn");
+  let cli = build_server_cli().get_matches_from(vec!["shs", "-v"]);
+  env::remove_var("SHED_LOG");
+  assert_eq!(Config::resolve(&cli).unwrap().log.level, "debug");
+}
+
+#[test]
+fn config_explain_env() {
+  let _env = ENV.lock().unwrap();
+  env::set_var("SHED_LOG", "warn");
+  let cli = build_server_cli().get_matches_from(vec!["shs", "--port", "9001"]);
+  env::remove_var("SHED_LOG");
+  let vals = Config::explain(&cli).unwrap();
+  let src = |key: &str| vals.iter().find(|(k, _, _)| k == key).unwrap().2;
+  assert_eq!(src("log.level"), "env");
+  assert_eq!(src("server.port"), "cli");
+}
+
+#[test]
+fn config_invalid() {
+  let cli = build_server_cli().get_matches_from(vec!["shs", "--log-level", "degub"]);
+  let e = Config::resolve(&cli).unwrap_err();
+  assert!(e.to_string().contains("log.level"));
+}
+
+#[test]
+fn config_check() {
+  let p = tmp_config(
+    "check.toml",
+    "bogus = 1\n[log]\nlevel = \"loud\"\n[server]\nport = \"x\"\n",
+  );
+  let issues = Config::check(&p).unwrap();
+  let fields: Vec<&str> = issues.iter().map(|(f, _)| f.as_str()).collect();
+  assert!(fields.contains(&"bogus"));
+  assert!(fields.contains(&"log.level"));
+  assert!(fields.contains(&"server.port"));
+}
+
+#[test]
+fn config_default() {
+  // every documented setting, uncommented, must be a valid config
+  let uncommented: String = shed::config::DEFAULT_CONFIG
+    .lines()
+    .map(|l| match l.strip_prefix("# ") {
+      Some(kv)
+        if kv
+          .split_once(" = ")
+          .map_or(false, |(k, _)| !k.contains(' ')) =>
+      {
+        kv
+      }
+      _ => l,
+    })
+    .map(|l| format!("{}\n", l))
+    .collect();
+  let p = tmp_config("default.toml", &uncommented);
+  assert_eq!(Config::check(&p).unwrap(), vec![]);
+}
+
+#[test]
+fn config_json() {
+  let p = tmp_config("config.json", "{\"log\": {\"level\": \"debug\"}}");
+  let cfg = Config::load(&p).unwrap();
+  assert_eq!(cfg.log.level, "debug");
+}
+
+#[test]
+fn config_expand_env() {
+  env::set_var("SHED_TEST_DATA", "/srv/shed");
+  let p = tmp_config(
+    "expand.toml",
+    "data_dir = \"${SHED_TEST_DATA}/data\"\n[log]\nfile = \"${SHED_TEST_LOGS:-/tmp}/shs.log\"\n",
+  );
+  let cfg = Config::load(&p).unwrap();
+  assert_eq!(cfg.data_dir, PathBuf::from("/srv/shed/data"));
+  assert_eq!(cfg.log.file, Some(PathBuf::from("/tmp/shs.log")));
+  let p = tmp_config("undefined.toml", "data_dir = \"${SHED_TEST_UNDEFINED}\"\n");
+  let issues = Config::check(&p).unwrap();
+  assert!(issues
+    .iter()
+    .any(|(f, e)| f == "data_dir" && e.contains("SHED_TEST_UNDEFINED")));
+}
@@ -0,0 +1,376 @@
+//! tests/server.rs --- shs server tests
+use shed::{server, Config, LogConfig, Reload, Server, ServerConfig, ServerError};
+use std::net::SocketAddr;
+use tokio::{sync::oneshot, task::JoinHandle};
+
+/// Stops a server started by `spawn`
+struct Shutdown(oneshot::Sender<()>, JoinHandle<server::Result<()>>);
+
+impl Shutdown {
+  /// Shut the server down, returning its result
+  async fn stop(self) -> server::Result<()> {
+    let _ = self.0.send(());
+    self.1.await.unwrap()
+  }
+}
+
+/// Run a bound server in the background
+fn spawn(server: Server) -> Shutdown {
+  let (tx, rx) = oneshot::channel::<()>();
+  let handle = tokio::spawn(server.run_until(async {
+    rx.await.ok();
+    Ok(())
+  }));
+  Shutdown(tx, handle)
+}
+
+/// Start a server for `cfg` on an ephemeral port, returning the
+/// address it is listening on
+async fn spawn_server(cfg: ServerConfig) -> (SocketAddr, Shutdown) {
+  let server = Server::bind(ServerConfig { port: 0, ..cfg }).await.unwrap();
+  let addr = server.local_addrs().unwrap()[0];
+  (addr, spawn(server))
+}
+
+/// GET `path` from the server at `addr`
+async fn get(addr: SocketAddr, path: &str) -> hyper::Response<hyper::Body> {
+  let uri: hyper::Uri = format!("http://{}{}", addr, path).parse().unwrap();
+  hyper::Client::new().get(uri).await.unwrap()
+}
+
+#[tokio::test]
+async fn server_shutdown() {
+  let cfg = ServerConfig {
+    drain_period: 0,
+    ..ServerConfig::default()
+  };
+  let (_, shutdown) = spawn_server(cfg).await;
+  assert!(shutdown.stop().await.is_ok());
+}
+
+#[tokio::test]
+async fn server_bind_in_use() {
+  let l = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+  let cfg = ServerConfig {
+    port: l.local_addr().unwrap().port(),
+    ..ServerConfig::default()
+  };
+  let e = server::run_until(cfg, async { Ok(()) }).await.unwrap_err();
+  assert!(matches!(e, ServerError::Bind { source, .. }
+    if source.kind() == std::io::ErrorKind::AddrInUse));
+}
+
+#[test]
+fn server_bad_addr() {
+  let cfg = ServerConfig {
+    bind: vec!["localhost".to_string()],
+    ..ServerConfig::default()
+  };
+  assert!(cfg.addrs().is_err());
+}
+
+#[tokio::test]
+async fn server_health() {
+  let cfg = ServerConfig {
+    drain_period: 0,
+    ..ServerConfig::default()
+  };
+  let (addr, shutdown) = spawn_server(cfg).await;
+  let health = get(addr, "/health").await;
+  let missing = get(addr, "/missing").await;
+  assert!(shutdown.stop().await.is_ok());
+  assert_eq!(health.status(), 200);
+  assert_eq!(missing.status(), 404);
+  let body = hyper::body::to_bytes(health.into_body()).await.unwrap();
+  assert_eq!(body, format!("{}\n", shed::version()));
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn server_unix_socket() {
+  let path = std::env::temp_dir().join(format!("shs-test-{}.sock", std::process::id()));
+  // a file which isn't a socket is never replaced
+  std::fs::write(&path, "notes").unwrap();
+  let cfg = ServerConfig {
+    socket: Some(path.clone()),
+    drain_period: 0,
+    ..ServerConfig::default()
+  };
+  let e = server::run_until(cfg.clone(), async { Ok(()) })
+    .await
+    .unwrap_err();
+  assert!(matches!(e, ServerError::Bind { .. }));
+  assert_eq!(std::fs::read_to_string(&path).unwrap(), "notes");
+  std::fs::remove_file(&path).unwrap();
+  // a leftover socket that nothing is listening on is stale
+  drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+  let shutdown = spawn(Server::bind(cfg).await.unwrap());
+  assert!(tokio::net::UnixStream::connect(&path).await.is_ok());
+  assert!(shutdown.stop().await.is_ok());
+  assert!(!path.exists());
+}
+
+#[test]
+fn server_reload() {
+  let mut reload = Reload::new(LogConfig::default(), None, || {
+    let mut cfg = Config::new();
+    cfg.server.port = 9001;
+    Ok(cfg)
+  });
+  let running = ServerConfig {
+    data_dir: Some(Config::new().data_dir),
+    ..ServerConfig::default()
+  };
+  assert_eq!(reload.reload(&running).unwrap(), vec!["server.port"]);
+}
+
+#[tokio::test]
+async fn server_metrics() {
+  let cfg = ServerConfig {
+    metrics: true,
+    drain_period: 0,
+    ..ServerConfig::default()
+  };
+  let (addr, shutdown) = spawn_server(cfg).await;
+  get(addr, "/health").await;
+  let metrics = get(addr, "/metrics").await;
+  assert!(shutdown.stop().await.is_ok());
+  assert_eq!(metrics.status(), 200);
+  let body = hyper::body::to_bytes(metrics.into_body()).await.unwrap();
+  let body = String::from_utf8(body.to_vec()).unwrap();
+  assert!(body.contains("shs_requests_total 2\n"));
+  assert!(body.contains("# TYPE shs_uptime_seconds gauge\n"));
+  assert!(body.contains(&format!(
+    "shs_build_info{{version=\"{}\"}} 1\n",
+    shed::version()
+  )));
+}
+
+#[tokio::test]
+async fn server_max_connections() {
+  use std::time::Duration;
+  use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    time::timeout,
+  };
+  let cfg = ServerConfig {
+    max_connections: 1,
+    drain_period: 0,
+    ..ServerConfig::default()
+  };
+  let (addr, shutdown) = spawn_server(cfg).await;
+  // keep a connection open to hold the only permit
+  let held = TcpStream::connect(addr).await.unwrap();
+  let mut waiting = TcpStream::connect(addr).await.unwrap();
+  waiting
+    .write_all(b"GET /health HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+    .await
+    .unwrap();
+  let mut buf = vec![];
+  let queued = timeout(Duration::from_millis(200), waiting.read_to_end(&mut buf)).await;
+  drop(held);
+  timeout(Duration::from_secs(5), waiting.read_to_end(&mut buf))
+    .await
+    .unwrap()
+    .unwrap();
+  assert!(shutdown.stop().await.is_ok());
+  assert!(queued.is_err(), "connection over the limit was served");
+  assert!(String::from_utf8_lossy(&buf).starts_with("HTTP/1.1 200"));
+}
+
+#[tokio::test]
+async fn server_shutdown_timeout() {
+  use std::time::{Duration, Instant};
+  use tokio::{io::AsyncWriteExt, net::TcpStream};
+  let cfg = ServerConfig {
+    shutdown_timeout: 1,
+    drain_period: 0,
+    ..ServerConfig::default()
+  };
+  let (addr, shutdown) = spawn_server(cfg).await;
+  // a request which never finishes
+  let mut stuck = TcpStream::connect(addr).await.unwrap();
+  stuck.write_all(b"GET /health HTTP/1.1\r\n").await.unwrap();
+  tokio::time::sleep(Duration::from_millis(50)).await;
+  let start = Instant::now();
+  assert!(shutdown.stop().await.is_ok());
+  assert!(start.elapsed() < Duration::from_secs(5));
+}
+
+#[tokio::test]
+async fn server_request_timeout() {
+  use std::time::Duration;
+  use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    time::timeout,
+  };
+  let cfg = ServerConfig {
+    request_timeout: 1,
+    drain_period: 0,
+    ..ServerConfig::default()
+  };
+  let (addr, shutdown) = spawn_server(cfg).await;
+  let mut slow = TcpStream::connect(addr).await.unwrap();
+  slow.write_all(b"GET /health HTTP/1.1\r\n").await.unwrap();
+  // the server closes the connection instead of waiting forever
+  let mut buf = vec![];
+  let closed = timeout(Duration::from_secs(5), slow.read_to_end(&mut buf)).await;
+  assert!(shutdown.stop().await.is_ok());
+  assert!(closed.is_ok(), "stalled connection wasn't closed");
+}
+
+#[tokio::test]
+async fn server_request_timeout_keep_alive() {
+  use std::time::{Duration, Instant};
+  use tokio::net::TcpStream;
+  let cfg = ServerConfig {
+    request_timeout: 1,
+    drain_period: 0,
+    ..ServerConfig::default()
+  };
+  let (addr, shutdown) = spawn_server(cfg).await;
+  let stream = TcpStream::connect(addr).await.unwrap();
+  let (mut tx, conn) = hyper::client::conn::handshake(stream).await.unwrap();
+  tokio::spawn(conn);
+  // a busy keep-alive connection outlives the request timeout
+  let start = Instant::now();
+  while start.elapsed() < Duration::from_millis(2500) {
+    let req = hyper::Request::get("/health")
+      .header("host", addr.to_string())
+      .body(hyper::Body::empty())
+      .unwrap();
+    let res = tx.send_request(req).await.unwrap();
+    assert_eq!(res.status(), 200);
+    hyper::body::to_bytes(res.into_body()).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(300)).await;
+  }
+  assert!(shutdown.stop().await.is_ok());
+}
+
+#[tokio::test]
+async fn server_bind_rollback() {
+  // the second bind of the same address only fails on a fixed port
+  let port = std::net::TcpListener::bind("127.0.0.1:0")
+    .unwrap()
+    .local_addr()
+    .unwrap()
+    .port();
+  let cfg = ServerConfig {
+    bind: vec!["127.0.0.1".to_string(), "127.0.0.1".to_string()],
+    port,
+    ..ServerConfig::default()
+  };
+  let e = server::run_until(cfg, async { Ok(()) }).await.unwrap_err();
+  assert!(matches!(e, ServerError::Bind { .. }));
+  // and the first is released
+  assert!(std::net::TcpListener::bind(("127.0.0.1", port)).is_ok());
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn server_pid_file() {
+  let path = std::env::temp_dir().join(format!("shs-test-{}.pid", std::process::id()));
+  // pid 1 is always running
+  std::fs::write(&path, "1\n").unwrap();
+  let cfg = ServerConfig {
+    pid_file: Some(path.clone()),
+    drain_period: 0,
+    ..ServerConfig::default()
+  };
+  let e = Server::bind(cfg.clone()).await.err().unwrap();
+  assert!(matches!(e, ServerError::PidFileInUse { pid: 1, .. }));
+  // values which aren't PIDs don't count as running
+  for garbage in ["0", "4294967295"] {
+    std::fs::write(&path, garbage).unwrap();
+    assert_eq!(server::pid_file_owner(&path), None);
+  }
+  // a stale pid file is replaced, and removed on shutdown
+  let mut dead = std::process::Command::new("true").spawn().unwrap();
+  dead.wait().unwrap();
+  std::fs::write(&path, dead.id().to_string()).unwrap();
+  let (_, shutdown) = spawn_server(cfg).await;
+  let pid = std::fs::read_to_string(&path).unwrap();
+  assert!(shutdown.stop().await.is_ok());
+  assert_eq!(pid.trim(), std::process::id().to_string());
+  assert!(!path.exists());
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn server_bind_fd() {
+  use std::os::unix::io::{AsRawFd, IntoRawFd};
+  let file = std::fs::File::open(env!("CARGO_MANIFEST_DIR")).unwrap();
+  let cfg = ServerConfig {
+    bind_fd: Some(file.as_raw_fd()),
+    ..ServerConfig::default()
+  };
+  let e = server::run_until(cfg, async { Ok(()) }).await.unwrap_err();
+  assert!(matches!(e, ServerError::Config(_)));
+  let l = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+  let bound = l.local_addr().unwrap();
+  let cfg = ServerConfig {
+    bind_fd: Some(l.into_raw_fd()),
+    drain_period: 0,
+    ..ServerConfig::default()
+  };
+  let (addr, shutdown) = spawn_server(cfg).await;
+  assert_eq!(addr, bound);
+  let health = get(addr, "/health").await;
+  assert!(shutdown.stop().await.is_ok());
+  assert_eq!(health.status(), 200);
+}
+
+#[tokio::test]
+async fn server_draining() {
+  use std::time::Duration;
+  use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+  };
+  let cfg = ServerConfig {
+    shutdown_timeout: 5,
+    drain_period: 0,
+    ..ServerConfig::default()
+  };
+  let (addr, shutdown) = spawn_server(cfg).await;
+  // start a request, and finish it once shutdown has begun
+  let mut s = TcpStream::connect(addr).await.unwrap();
+  s.write_all(b"GET /health HTTP/1.1\r\n").await.unwrap();
+  tokio::time::sleep(Duration::from_millis(50)).await;
+  let client = async {
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    s.write_all(b"Host: localhost\r\n\r\n").await.unwrap();
+    let mut buf = vec![];
+    s.read_to_end(&mut buf).await.unwrap();
+    String::from_utf8_lossy(&buf).to_string()
+  };
+  let (res, body) = tokio::join!(shutdown.stop(), client);
+  assert!(res.is_ok());
+  assert!(
+    body.starts_with("HTTP/1.1 503"),
+    "unexpected response: {}",
+    body
+  );
+  assert!(body.ends_with("draining\n"));
+}
+
+#[tokio::test]
+async fn server_draining_new_connection() {
+  let cfg = ServerConfig {
+    drain_period: 1,
+    ..ServerConfig::default()
+  };
+  let (addr, shutdown) = spawn_server(cfg).await;
+  let client = async {
+    tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    // a load balancer probing during the drain connects afresh
+    get(addr, "/health").await
+  };
+  let (res, health) = tokio::join!(shutdown.stop(), client);
+  assert!(res.is_ok());
+  assert_eq!(health.status(), 503);
+  let body = hyper::body::to_bytes(health.into_body()).await.unwrap();
+  assert_eq!(body, "draining\n");
+}
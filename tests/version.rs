@@ -0,0 +1,25 @@
+//! tests/version.rs --- build metadata tests
+use std::path::Path;
+
+#[test]
+fn demon_version_set() {
+  // DEMON_VERSION is only guaranteed to carry a hash when building
+  // from a Mercurial checkout.
+  if Path::new(env!("CARGO_MANIFEST_DIR")).join(".hg").exists() {
+    let v = option_env!("DEMON_VERSION").unwrap_or_default();
+    assert!(!v.is_empty());
+    assert_eq!(shed::version(), v);
+  } else {
+    assert!(shed::version().starts_with(env!("CARGO_PKG_VERSION")));
+  }
+}
+
+#[test]
+fn build_info() {
+  let info: std::collections::HashMap<_, _> = shed::BUILD_INFO.into_iter().collect();
+  // an RFC 3339 UTC timestamp like 2021-11-23T04:46:00Z
+  let built = info["built"];
+  assert_eq!(built.len(), 20);
+  assert!(built.ends_with('Z'));
+  assert!(!info["host"].is_empty());
+}